@@ -1,18 +1,325 @@
 use crate::error::Error;
-use flate2::read::GzDecoder;
+use crate::oci::DigestWriter;
+use crate::utils::hash_str;
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::{self, File};
-use std::io::Read;
-use std::path::Path;
-use tempfile::tempdir_in;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tempfile::{tempdir_in, NamedTempFile};
+
+/// The maximum number of symbolic links to follow when resolving a path, like `MAXSYMLINKS`
+/// on Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// Limits that guard against archive bombs when extracting (or decompressing) a resource.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ExtractionLimits {
+    /// The maximum total size, in bytes, of the extracted files.
+    pub(crate) max_size: Option<u64>,
+    /// The maximum number of entries in an archive.
+    pub(crate) max_entries: Option<u64>,
+    /// The maximum ratio between the total size of the extracted files and the size
+    /// of the archive.
+    pub(crate) max_ratio: Option<f64>,
+}
+
+/// How the permissions, modification times and extended attributes of extracted
+/// files are set. Tar, zip and 7z archives are treated the same way.
+///
+/// This can be set with [`Options::metadata()`](crate::Options::metadata).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataPolicy {
+    /// Keep the read, write and execute permissions and the modification times stored
+    /// in the archive, but not the setuid, setgid and sticky bits or extended attributes.
+    #[default]
+    Default,
+    /// Keep all of the permissions and the modification times stored in the archive,
+    /// as well as extended attributes on Unix (zip archives don't have any).
+    Preserve,
+    /// Make every file read-only, keeping only whether it's executable, and give
+    /// directories the usual `rwxr-xr-x` permissions. Modification times are the time
+    /// of extraction.
+    ReadOnly,
+}
+
+impl MetadataPolicy {
+    fn name(&self) -> &'static str {
+        match self {
+            MetadataPolicy::Default => "default",
+            MetadataPolicy::Preserve => "preserve",
+            MetadataPolicy::ReadOnly => "read-only",
+        }
+    }
+
+    /// The permissions to give an extracted zip or 7z entry with the given Unix mode, if any.
+    #[cfg(unix)]
+    fn entry_mode(&self, mode: Option<u32>, is_dir: bool) -> Option<u32> {
+        match self {
+            MetadataPolicy::Default => mode.map(|mode| mode & 0o777),
+            MetadataPolicy::Preserve => mode.map(|mode| mode & 0o7777),
+            MetadataPolicy::ReadOnly if is_dir => Some(0o755),
+            // Files are made read-only once they're written.
+            MetadataPolicy::ReadOnly => None,
+        }
+    }
+}
+
+impl std::str::FromStr for MetadataPolicy {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "default" => Ok(MetadataPolicy::Default),
+            "preserve" => Ok(MetadataPolicy::Preserve),
+            "read-only" => Ok(MetadataPolicy::ReadOnly),
+            _ => Err(Error::ConfigurationError(format!(
+                "unknown metadata policy '{name}'"
+            ))),
+        }
+    }
+}
+
+/// What to do with symlinks and hard links in an archive. Links that point outside of
+/// the extraction directory are always rejected with an [`Error::UnsafeArchiveEntry`].
+///
+/// This can be set with [`Options::links()`](crate::Options::links).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkPolicy {
+    /// Unpack links as links.
+    #[default]
+    Keep,
+    /// Replace links with copies of the files or directories they point to, so that
+    /// the extraction directory doesn't contain any links.
+    Dereference,
+    /// Fail with an [`Error::UnsafeArchiveEntry`] if the archive contains any links.
+    Reject,
+}
+
+impl LinkPolicy {
+    fn name(&self) -> &'static str {
+        match self {
+            LinkPolicy::Keep => "keep",
+            LinkPolicy::Dereference => "dereference",
+            LinkPolicy::Reject => "reject",
+        }
+    }
+}
+
+impl std::str::FromStr for LinkPolicy {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "keep" => Ok(LinkPolicy::Keep),
+            "dereference" => Ok(LinkPolicy::Dereference),
+            "reject" => Ok(LinkPolicy::Reject),
+            _ => Err(Error::ConfigurationError(format!(
+                "unknown link policy '{name}'"
+            ))),
+        }
+    }
+}
+
+/// Options that control how the entries of an archive are unpacked.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UnpackOptions {
+    pub(crate) limits: ExtractionLimits,
+    /// If not empty, only entries matching one of these patterns are unpacked.
+    pub(crate) include: Vec<Pattern>,
+    /// Entries matching any of these patterns are skipped.
+    pub(crate) exclude: Vec<Pattern>,
+    /// The number of leading path components to remove from each entry.
+    pub(crate) strip_components: usize,
+    /// If everything is unpacked into a single top-level directory, use the contents
+    /// of that directory instead.
+    pub(crate) unwrap_single_dir: bool,
+    /// How many levels of archives inside of the archive to unpack as well.
+    pub(crate) max_depth: usize,
+    /// Where to report the progress of unpacking the outermost archive.
+    pub(crate) progress: Option<Progress>,
+    /// How many threads to write the files of a zip archive with.
+    pub(crate) threads: usize,
+    /// How to set the permissions and timestamps of the unpacked files.
+    pub(crate) metadata: MetadataPolicy,
+    /// What to do with links in the archive.
+    pub(crate) links: LinkPolicy,
+}
+
+/// Receives updates on the progress of an extraction, e.g. to display a progress bar.
+pub(crate) trait ExtractionProgress: Send {
+    /// Called with the number of bytes of the archive read from disk.
+    fn read(&mut self, bytes: usize);
+
+    /// Called after each entry is written to disk.
+    fn unpacked(&mut self);
+
+    fn finish(&mut self);
+
+    /// Called instead of [`ExtractionProgress::finish`] when the extraction stops early,
+    /// e.g. because of an error.
+    fn abandon(&mut self);
+}
+
+/// A handle to an [`ExtractionProgress`] that can be shared between the reader of the
+/// archive and the unpacker. If the last handle is dropped before the extraction is
+/// finished, the progress is abandoned.
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<Mutex<ProgressGuard>>);
+
+struct ProgressGuard {
+    progress: Box<dyn ExtractionProgress>,
+    finished: bool,
+}
+
+impl Drop for ProgressGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.progress.abandon();
+        }
+    }
+}
+
+impl Progress {
+    #[cfg_attr(not(feature = "progress-bar"), allow(dead_code))]
+    pub(crate) fn new<P: ExtractionProgress + 'static>(progress: P) -> Self {
+        Progress(Arc::new(Mutex::new(ProgressGuard {
+            progress: Box::new(progress),
+            finished: false,
+        })))
+    }
+
+    fn update<F: FnOnce(&mut dyn ExtractionProgress)>(&self, f: F) {
+        if let Ok(mut guard) = self.0.lock() {
+            f(&mut *guard.progress);
+        }
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Ok(mut guard) = self.0.lock() {
+            if !guard.finished {
+                guard.progress.finish();
+                guard.finished = true;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Reports the bytes read from the underlying reader to a [`Progress`].
+struct ProgressReader<R> {
+    reader: R,
+    progress: Option<Progress>,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if let Some(progress) = &self.progress {
+            progress.update(|progress| progress.read(n));
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
+
+impl UnpackOptions {
+    /// Check if an entry should be unpacked according to the include and exclude patterns.
+    fn includes(&self, path: &Path) -> bool {
+        // Like in a shell, '*' shouldn't match across directories. Use '**' for that.
+        let options = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        (self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, options)))
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.matches_path_with(path, options))
+    }
+
+    /// Remove the leading path components of an entry according to `strip_components`,
+    /// returning `None` if nothing is left.
+    fn strip(&self, path: &Path) -> Option<PathBuf> {
+        let stripped: PathBuf = path.components().skip(self.strip_components).collect();
+        if stripped.as_os_str().is_empty() {
+            None
+        } else {
+            Some(stripped)
+        }
+    }
+
+    /// A short string identifying the options that change which files are extracted
+    /// and where they end up, or `None` if all files are extracted as-is. This is used to give each distinct
+    /// extraction of an archive its own directory.
+    pub(crate) fn key(&self) -> Option<String> {
+        let mut parts: Vec<String> = Vec::new();
+        let mut include: Vec<&str> = self.include.iter().map(Pattern::as_str).collect();
+        include.sort_unstable();
+        parts.extend(include.into_iter().map(|p| format!("include={p}")));
+        let mut exclude: Vec<&str> = self.exclude.iter().map(Pattern::as_str).collect();
+        exclude.sort_unstable();
+        parts.extend(exclude.into_iter().map(|p| format!("exclude={p}")));
+        if self.strip_components > 0 {
+            parts.push(format!("strip_components={}", self.strip_components));
+        }
+        if self.unwrap_single_dir {
+            parts.push("unwrap_single_dir".to_string());
+        }
+        if self.max_depth > 0 {
+            parts.push(format!("max_depth={}", self.max_depth));
+        }
+        if self.metadata != MetadataPolicy::Default {
+            parts.push(format!("metadata={}", self.metadata.name()));
+        }
+        if self.links != LinkPolicy::Keep {
+            parts.push(format!("links={}", self.links.name()));
+        }
+        if parts.is_empty() {
+            None
+        } else {
+            Some(hash_str(&parts.join("\n"))[..16].to_string())
+        }
+    }
+}
 
 /// Supported archive types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum ArchiveFormat {
+    Tar,
     TarGz,
-    #[cfg(feature = "lzma")]
+    #[cfg(any(feature = "lzma", feature = "xz-native"))]
     TarXz,
-    #[cfg(feature = "lzma")]
+    #[cfg(any(feature = "lzma", feature = "xz-native"))]
     TarLzma,
+    #[cfg(feature = "bzip2")]
+    TarBz2,
+    #[cfg(feature = "zstd")]
+    TarZst,
     Zip,
+    #[cfg(feature = "sevenz")]
+    SevenZ,
 }
 
 // see https://github.com/bojand/infer/issues/91
@@ -50,28 +357,41 @@ impl ArchiveFormat {
     pub(crate) fn parse_from_extension(resource: &Path) -> Result<Self, Error> {
         if let Some(file_type) = infer().get_from_path(resource)? {
             let archive_type = match file_type.mime_type() {
+                "application/x-tar" => Self::Tar,
                 "application/gzip" if Self::is_tar(&mut GzDecoder::new(File::open(resource)?)) => {
                     Self::TarGz
                 }
-                #[cfg(feature = "lzma")]
+                #[cfg(any(feature = "lzma", feature = "xz-native"))]
                 "application/x-xz"
-                    if Self::is_tar(&mut lzma::LzmaDecoder::new(
+                    if Self::is_tar(&mut lzma::decoder(
                         lzma::Codec::Xz,
                         File::open(resource)?,
                     )?) =>
                 {
                     Self::TarXz
                 }
-                #[cfg(feature = "lzma")]
+                #[cfg(any(feature = "lzma", feature = "xz-native"))]
                 "application/x-lzma"
-                    if Self::is_tar(&mut lzma::LzmaDecoder::new(
+                    if Self::is_tar(&mut lzma::decoder(
                         lzma::Codec::Lzma,
                         File::open(resource)?,
                     )?) =>
                 {
                     Self::TarLzma
                 }
+                #[cfg(feature = "bzip2")]
+                "application/x-bzip2"
+                    if Self::is_tar(&mut bzip2::read::BzDecoder::new(File::open(resource)?)) =>
+                {
+                    Self::TarBz2
+                }
+                #[cfg(feature = "zstd")]
+                "application/zstd" if Self::is_tar(&mut zstd_decoder(File::open(resource)?)?) => {
+                    Self::TarZst
+                }
                 "application/zip" => Self::Zip,
+                #[cfg(feature = "sevenz")]
+                "application/x-7z-compressed" => Self::SevenZ,
                 tpe => {
                     return Err(Error::ExtractionError(format!(
                         "unsupported file format: {tpe}"
@@ -85,145 +405,1798 @@ impl ArchiveFormat {
             ))
         }
     }
+
+    /// Wrap the file of a tar-based archive with the decoder for its tar stream.
+    fn tar_decoder<R: Read + Send + 'static>(&self, reader: R) -> Result<Box<dyn Read>, Error> {
+        Ok(match self {
+            ArchiveFormat::Tar => Box::new(reader),
+            ArchiveFormat::TarGz => Box::new(GzDecoder::new(reader)),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            ArchiveFormat::TarXz => lzma::decoder(lzma::Codec::Xz, reader)?,
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            ArchiveFormat::TarLzma => lzma::decoder(lzma::Codec::Lzma, reader)?,
+            #[cfg(feature = "bzip2")]
+            ArchiveFormat::TarBz2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+            #[cfg(feature = "zstd")]
+            ArchiveFormat::TarZst => Box::new(zstd_decoder(reader)?),
+            ArchiveFormat::Zip => {
+                return Err(Error::ExtractionError("not a tar archive".into()));
+            }
+            #[cfg(feature = "sevenz")]
+            ArchiveFormat::SevenZ => {
+                return Err(Error::ExtractionError("not a tar archive".into()));
+            }
+        })
+    }
+
+    /// Guess the format of a tar-based archive from its first bytes, without checking
+    /// that the compressed data is actually a tar archive.
+    fn detect_tar(head: &[u8]) -> Option<Self> {
+        match infer().get(head)?.mime_type() {
+            "application/x-tar" => Some(ArchiveFormat::Tar),
+            "application/gzip" => Some(ArchiveFormat::TarGz),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            "application/x-xz" => Some(ArchiveFormat::TarXz),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            "application/x-lzma" => Some(ArchiveFormat::TarLzma),
+            #[cfg(feature = "bzip2")]
+            "application/x-bzip2" => Some(ArchiveFormat::TarBz2),
+            #[cfg(feature = "zstd")]
+            "application/zstd" => Some(ArchiveFormat::TarZst),
+            _ => None,
+        }
+    }
+
+    /// The name of the format, as accepted by [`str::parse`].
+    fn name(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarGz => "tar.gz",
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            ArchiveFormat::TarXz => "tar.xz",
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            ArchiveFormat::TarLzma => "tar.lzma",
+            #[cfg(feature = "bzip2")]
+            ArchiveFormat::TarBz2 => "tar.bz2",
+            #[cfg(feature = "zstd")]
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+            #[cfg(feature = "sevenz")]
+            ArchiveFormat::SevenZ => "7z",
+        }
+    }
+
+    /// Check that the file at `path` is actually an archive of this format, for when
+    /// the format is given instead of detected.
+    pub(crate) fn verify(&self, path: &Path) -> Result<(), Error> {
+        let file = File::open(path)?;
+        let result = match self {
+            ArchiveFormat::Zip => zip::ZipArchive::new(file)
+                .map(|_| ())
+                .map_err(|e| Error::ExtractionError(e.to_string())),
+            #[cfg(feature = "sevenz")]
+            ArchiveFormat::SevenZ => {
+                drop(file);
+                sevenz_rust::Archive::open(path)
+                    .map(|_| ())
+                    .map_err(|e| Error::ExtractionError(e.to_string()))
+            }
+            // Reading the first header is enough to tell, since it has a checksum.
+            _ => self.tar_decoder(file).and_then(|decoder| {
+                let mut archive = tar::Archive::new(decoder);
+                archive.entries()?.next().transpose()?;
+                Ok(())
+            }),
+        };
+        result.map_err(|e| {
+            Error::ExtractionError(format!(
+                "{path:?} is not a valid {} archive: {e}",
+                self.name()
+            ))
+        })
+    }
+}
+
+impl std::str::FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Ok(ArchiveFormat::TarGz),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            "tar.xz" | "txz" => Ok(ArchiveFormat::TarXz),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            "tar.lzma" | "tlz" => Ok(ArchiveFormat::TarLzma),
+            #[cfg(feature = "bzip2")]
+            "tar.bz2" | "tbz2" => Ok(ArchiveFormat::TarBz2),
+            #[cfg(feature = "zstd")]
+            "tar.zst" | "tzst" => Ok(ArchiveFormat::TarZst),
+            "zip" => Ok(ArchiveFormat::Zip),
+            #[cfg(feature = "sevenz")]
+            "7z" => Ok(ArchiveFormat::SevenZ),
+            _ => {
+                let feature = match name {
+                    "tar.xz" | "txz" | "tar.lzma" | "tlz" => "lzma' or 'xz-native",
+                    "tar.bz2" | "tbz2" => "bzip2",
+                    "tar.zst" | "tzst" => "zstd",
+                    "7z" => "sevenz",
+                    _ => {
+                        return Err(Error::ConfigurationError(format!(
+                            "unknown archive format '{name}'"
+                        )))
+                    }
+                };
+                Err(Error::ConfigurationError(format!(
+                    "archive format '{name}' requires the '{feature}' feature"
+                )))
+            }
+        }
+    }
+}
+
+/// An entry of an archive, as listed by [`ArchiveReader::entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// The path of the entry within the archive.
+    pub path: PathBuf,
+    /// The uncompressed size of the entry, in bytes.
+    pub size: u64,
+    /// Whether the entry is a directory.
+    pub is_dir: bool,
+}
+
+/// Reads the entries of an archive without extracting it to disk.
+///
+/// This supports the same archive formats as [`Options::extract`](crate::Options::extract).
+/// Members of zip archives are read directly, while members of tar-based (and 7z) archives
+/// are streamed by decoding the archive up to the member.
+///
+/// ```rust,no_run
+/// use cached_path::{cached_path, ArchiveReader};
+/// use std::io::Read;
+///
+/// let path = cached_path("https://example.com/data.tar.gz").unwrap();
+/// let mut archive = ArchiveReader::open(&path).unwrap();
+/// for entry in archive.entries().unwrap() {
+///     println!("{:?} ({} bytes)", entry.path, entry.size);
+/// }
+/// let mut contents = String::new();
+/// archive
+///     .open_member("data/train.jsonl")
+///     .unwrap()
+///     .read_to_string(&mut contents)
+///     .unwrap();
+/// ```
+pub struct ArchiveReader {
+    path: PathBuf,
+    format: ArchiveFormat,
+    /// Zip archives are kept open so that their members can be read in any order.
+    zip: Option<zip::ZipArchive<File>>,
+}
+
+impl ArchiveReader {
+    /// Open an archive, detecting its format from its contents.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        let format = ArchiveFormat::parse_from_extension(&path)?;
+        let zip = match format {
+            ArchiveFormat::Zip => Some(
+                zip::ZipArchive::new(File::open(&path)?)
+                    .map_err(|e| Error::ExtractionError(e.to_string()))?,
+            ),
+            _ => None,
+        };
+        Ok(ArchiveReader { path, format, zip })
+    }
+
+    /// List the entries of the archive.
+    pub fn entries(&mut self) -> Result<Vec<ArchiveEntry>, Error> {
+        if let Some(zip) = &mut self.zip {
+            return (0..zip.len())
+                .map(|i| {
+                    let file = zip
+                        .by_index_raw(i)
+                        .map_err(|e| Error::ExtractionError(e.to_string()))?;
+                    Ok(ArchiveEntry {
+                        path: PathBuf::from(file.name()),
+                        size: file.size(),
+                        is_dir: file.is_dir(),
+                    })
+                })
+                .collect();
+        }
+
+        #[cfg(feature = "sevenz")]
+        if let ArchiveFormat::SevenZ = self.format {
+            let archive = sevenz_rust::Archive::open(&self.path)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            return Ok(archive
+                .files
+                .iter()
+                .map(|entry| ArchiveEntry {
+                    path: PathBuf::from(entry.name()),
+                    size: entry.size(),
+                    is_dir: entry.is_directory(),
+                })
+                .collect());
+        }
+
+        let mut archive = tar::Archive::new(self.format.tar_decoder(File::open(&self.path)?)?);
+        let entries = archive
+            .entries()?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(ArchiveEntry {
+                    path: entry.path()?.into_owned(),
+                    size: entry.size(),
+                    is_dir: entry.header().entry_type().is_dir(),
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Open a file in the archive for reading.
+    ///
+    /// Returns an [`Error::ArchiveMemberNotFound`] if the archive doesn't contain the file.
+    pub fn open_member<P: AsRef<Path>>(&mut self, member: P) -> Result<Box<dyn Read + '_>, Error> {
+        let member = member.as_ref();
+        let archive_path = self.path.clone();
+        let not_found = || Error::ArchiveMemberNotFound(format!("{member:?} in {archive_path:?}"));
+        let not_a_file = || Error::ExtractionError(format!("{member:?} is not a regular file"));
+        let wanted = entry_path(member).map_err(|_| not_found())?;
+        let matches = |path: &Path| entry_path(path).is_ok_and(|path| path == wanted);
+
+        if let Some(zip) = &mut self.zip {
+            // Look the member up by name, only falling back to comparing the normalized names
+            // of all entries for directories and entries with names like './data.txt'.
+            let index = zip
+                .index_for_path(&wanted)
+                .or_else(|| zip.file_names().position(|name| matches(Path::new(name))))
+                .ok_or_else(not_found)?;
+            let file = zip
+                .by_index_raw(index)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            if file.is_dir() || file.is_symlink() {
+                return Err(not_a_file());
+            }
+            drop(file);
+            let file = zip
+                .by_index(index)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            return Ok(Box::new(file));
+        }
+
+        #[cfg(feature = "sevenz")]
+        if let ArchiveFormat::SevenZ = self.format {
+            let archive = sevenz_rust::Archive::open(&self.path)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            let entry = archive
+                .files
+                .iter()
+                .find(|entry| matches(Path::new(entry.name())))
+                .ok_or_else(not_found)?;
+            if entry.is_directory() {
+                return Err(not_a_file());
+            }
+            // 7z only gives access to the contents of an entry through a callback, so
+            // we stream it from a background thread.
+            let path = self.path.clone();
+            return Ok(Box::new(BackgroundReader::spawn(move |writer| {
+                let mut reader =
+                    sevenz_rust::SevenZReader::open(&path, sevenz_rust::Password::empty())
+                        .map_err(|e| io::Error::other(e.to_string()))?;
+                reader
+                    .for_each_entries(|entry, reader| {
+                        if entry_path(Path::new(entry.name())).is_ok_and(|path| path == wanted) {
+                            io::copy(reader, writer).map_err(sevenz_rust::Error::io)?;
+                            Ok(false)
+                        } else {
+                            // Entries have to be read in full for their checksums to verify.
+                            io::copy(reader, &mut io::sink()).map_err(sevenz_rust::Error::io)?;
+                            Ok(true)
+                        }
+                    })
+                    .map_err(|e| io::Error::other(e.to_string()))
+            })?));
+        }
+
+        let mut archive = tar::Archive::new(self.format.tar_decoder(File::open(&self.path)?)?);
+        let mut size = None;
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if matches(&entry.path()?) {
+                if !entry.header().entry_type().is_file() {
+                    return Err(not_a_file());
+                }
+                size = Some(entry.size());
+                break;
+            }
+        }
+        let size = size.ok_or_else(not_found)?;
+        // The tar headers of the member have been read, so the underlying reader is now
+        // positioned at the start of its contents.
+        Ok(Box::new(archive.into_inner().take(size)))
+    }
+}
+
+/// Streams the data written by a function running in a background thread, for APIs
+/// that only give access to data through a callback.
+#[cfg(feature = "sevenz")]
+struct BackgroundReader {
+    handle: Option<std::thread::JoinHandle<io::Result<()>>>,
+    pipe_reader: io::PipeReader,
+}
+
+#[cfg(feature = "sevenz")]
+impl BackgroundReader {
+    fn spawn<F>(f: F) -> io::Result<Self>
+    where
+        F: FnOnce(&mut io::PipeWriter) -> io::Result<()> + Send + 'static,
+    {
+        let (pipe_reader, mut pipe_writer) = io::pipe()?;
+        let handle = std::thread::spawn(move || f(&mut pipe_writer));
+        Ok(BackgroundReader {
+            handle: Some(handle),
+            pipe_reader,
+        })
+    }
+}
+
+#[cfg(feature = "sevenz")]
+impl Read for BackgroundReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.pipe_reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // The writing end is closed, so the thread is done. Report any error it ran into.
+            if let Some(handle) = self.handle.take() {
+                handle
+                    .join()
+                    .map_err(|_| io::Error::other("background thread panicked"))??;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Supported compression formats for single (non-archive) files.
+pub(crate) enum CompressionFormat {
+    Gzip,
+    #[cfg(any(feature = "lzma", feature = "xz-native"))]
+    Xz,
+    #[cfg(any(feature = "lzma", feature = "xz-native"))]
+    Lzma,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl CompressionFormat {
+    /// Parse the compression format from the magic number of the resource.
+    pub(crate) fn parse_from_extension(resource: &Path) -> Result<Self, Error> {
+        if let Some(file_type) = infer().get_from_path(resource)? {
+            let compression_type = match file_type.mime_type() {
+                "application/gzip" => Self::Gzip,
+                #[cfg(any(feature = "lzma", feature = "xz-native"))]
+                "application/x-xz" => Self::Xz,
+                #[cfg(any(feature = "lzma", feature = "xz-native"))]
+                "application/x-lzma" => Self::Lzma,
+                #[cfg(feature = "bzip2")]
+                "application/x-bzip2" => Self::Bzip2,
+                #[cfg(feature = "zstd")]
+                "application/zstd" => Self::Zstd,
+                tpe => {
+                    return Err(Error::ExtractionError(format!(
+                        "unsupported compression format: {tpe}"
+                    )))
+                }
+            };
+            Ok(compression_type)
+        } else {
+            Err(Error::ExtractionError(
+                "cannot determine compression format".into(),
+            ))
+        }
+    }
+
+    /// Wrap a reader of compressed data with the right decoder.
+    fn decoder(&self, file: File) -> io::Result<Box<dyn Read>> {
+        Ok(match self {
+            // Gzip and bzip2 files may consist of several concatenated streams, e.g. when
+            // created by `bgzip` or `pbzip2`.
+            CompressionFormat::Gzip => Box::new(MultiGzDecoder::new(file)),
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            CompressionFormat::Xz => lzma::decoder(lzma::Codec::Xz, file)?,
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            CompressionFormat::Lzma => lzma::decoder(lzma::Codec::Lzma, file)?,
+            #[cfg(feature = "bzip2")]
+            CompressionFormat::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+            #[cfg(feature = "zstd")]
+            CompressionFormat::Zstd => Box::new(zstd_decoder(file)?),
+        })
+    }
+}
+
+/// Decompress a single compressed file to `target`.
+pub(crate) fn decompress_file<P: AsRef<Path>>(
+    path: P,
+    target: P,
+    format: &CompressionFormat,
+    limits: &ExtractionLimits,
+) -> Result<(), Error> {
+    // Like with extraction, we first decompress to a temp file in the same parent as the
+    // target so that a partially decompressed file is never visible.
+    let target_parent_dir = target.as_ref().parent().unwrap();
+    let mut temp_target = NamedTempFile::new_in(target_parent_dir)?;
+
+    let mut budget = Budget::new(limits);
+    budget.archive_size = fs::metadata(path.as_ref())?.len();
+    let mut decoder = format.decoder(File::open(path)?)?;
+    budget.copy(&mut decoder, &mut temp_target)?;
+
+    temp_target.persist(target).map_err(|e| e.error)?;
+
+    Ok(())
 }
 
 pub(crate) fn extract_archive<P: AsRef<Path>>(
     path: P,
     target: P,
     format: &ArchiveFormat,
+    options: &UnpackOptions,
 ) -> Result<(), Error> {
     // We'll first extract to a temp directory in the same parent as the target directory.
     let target_parent_dir = target.as_ref().parent().unwrap();
     let temp_target = tempdir_in(target_parent_dir)?;
 
-    match format {
-        ArchiveFormat::TarGz => {
-            let tar_gz = File::open(path)?;
-            let tar = GzDecoder::new(tar_gz);
-            let mut archive = tar::Archive::new(tar);
-            archive.unpack(&temp_target)?;
-        }
-        #[cfg(feature = "lzma")]
-        ArchiveFormat::TarXz => {
-            let xz_decoder = lzma::LzmaDecoder::new(lzma::Codec::Xz, File::open(path)?)?;
-            let mut archive = tar::Archive::new(xz_decoder);
-            archive.unpack(&temp_target)?;
-        }
-        #[cfg(feature = "lzma")]
-        ArchiveFormat::TarLzma => {
-            let lzma_decoder = lzma::LzmaDecoder::new(lzma::Codec::Lzma, File::open(path)?)?;
-            let mut archive = tar::Archive::new(lzma_decoder);
-            archive.unpack(&temp_target)?;
-        }
-        ArchiveFormat::Zip => {
-            let file = File::open(path)?;
-            let mut archive =
-                zip::ZipArchive::new(file).map_err(|e| Error::ExtractionError(e.to_string()))?;
-            archive
-                .extract(temp_target.path())
-                .map_err(|e| Error::ExtractionError(e.to_string()))?;
-        }
-    };
+    unpack_archive(path.as_ref(), temp_target.path(), format, options)?;
 
     // Now rename the temp directory to the final target directory.
-    fs::rename(temp_target, target)?;
+    fs::rename(unpacked_root(temp_target.path(), options)?, target)?;
 
     Ok(())
 }
 
-#[cfg(feature = "lzma")]
-mod lzma {
-    use std::io::Read;
-    use std::thread::JoinHandle;
+/// Extract the layers of an OCI artifact, in order, into a single directory. Layers
+/// that aren't archives are copied as-is.
+pub(crate) fn extract_layers(
+    layers: &[PathBuf],
+    target: &Path,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    let target_parent_dir = target.parent().unwrap();
+    let temp_target = tempdir_in(target_parent_dir)?;
 
-    #[derive(Clone, Copy)]
-    pub(super) enum Codec {
-        Lzma,
-        Xz,
+    // The limits apply to the artifact as a whole rather than to each layer.
+    let mut budget = Budget::new(&options.limits);
+    for layer in layers {
+        match ArchiveFormat::parse_from_extension(layer) {
+            Ok(format) => {
+                unpack_archive_with(layer, temp_target.path(), &format, options, &mut budget)?
+            }
+            Err(Error::ExtractionError(_)) => {
+                let file_name = layer.file_name().unwrap();
+                if options.includes(Path::new(file_name)) {
+                    budget.add_entry()?;
+                    let mut reader = File::open(layer)?;
+                    budget.archive_size += reader.metadata()?.len();
+                    let mut writer = File::create(temp_target.path().join(file_name))?;
+                    budget.copy(&mut reader, &mut writer)?;
+                }
+            }
+            Err(err) => return Err(err),
+        }
     }
+    unpack_nested(temp_target.path(), options, options.max_depth, &mut budget)?;
 
-    impl std::fmt::Display for Codec {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            match self {
-                Codec::Lzma => write!(f, "lzma"),
-                Codec::Xz => write!(f, "xz"),
-            }
+    fs::rename(unpacked_root(temp_target.path(), options)?, target)?;
+
+    Ok(())
+}
+
+/// A writer that also extracts everything written to it as a tar-based archive, so that
+/// an archive can be extracted while it's being downloaded instead of read again afterwards.
+///
+/// The extraction happens on a background thread fed through a pipe. If it fails, e.g.
+/// because the data isn't a tar-based archive after all, the rest of the data is still
+/// written and the error is returned from [`ExtractingWriter::finish`].
+pub(crate) struct ExtractingWriter<W: Write> {
+    writer: W,
+    extraction: Option<Extraction>,
+}
+
+struct Extraction {
+    /// `None` once the extraction has stopped reading.
+    pipe: Option<io::PipeWriter>,
+    handle: Option<std::thread::JoinHandle<Result<Budget, Error>>>,
+    temp_target: tempfile::TempDir,
+    options: UnpackOptions,
+    /// The number of bytes of the archive written so far.
+    size: u64,
+}
+
+impl<W: Write> ExtractingWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        ExtractingWriter {
+            writer,
+            extraction: None,
         }
     }
 
-    pub(super) struct LzmaDecoder {
-        codec: Codec,
-        decoder_handle: Option<JoinHandle<Result<(), lzma_rs::error::Error>>>,
-        pipe_reader: std::io::PipeReader,
+    /// Start extracting the data into a temp directory next to `target`. The format is
+    /// detected from the first bytes written if it isn't given.
+    pub(crate) fn extract(
+        &mut self,
+        target: &Path,
+        format: Option<ArchiveFormat>,
+        options: &UnpackOptions,
+    ) -> Result<(), Error> {
+        let temp_target = tempdir_in(target.parent().unwrap())?;
+        let (pipe_reader, pipe_writer) = io::pipe()?;
+        let mut rest = pipe_reader.try_clone()?;
+        let dst = temp_target.path().to_path_buf();
+        let thread_options = options.clone();
+        let handle = std::thread::spawn(move || {
+            let result = unpack_tar_stream(pipe_reader, &dst, format, &thread_options);
+            // Keep reading until the end, even if we stopped early, so that writing
+            // never fails with a broken pipe.
+            io::copy(&mut rest, &mut io::sink())?;
+            result
+        });
+        self.extraction = Some(Extraction {
+            pipe: Some(pipe_writer),
+            handle: Some(handle),
+            temp_target,
+            options: options.clone(),
+            size: 0,
+        });
+        Ok(())
     }
 
-    impl LzmaDecoder {
-        pub(super) fn new<R: Read + Send + 'static>(
-            codec: Codec,
-            reader: R,
-        ) -> std::io::Result<Self> {
-            let (pipe_reader, mut pipe_writer) = std::io::pipe()?;
-            let decoder_handle = std::thread::spawn(move || {
-                let mut reader = std::io::BufReader::new(reader);
-                match codec {
-                    Codec::Lzma => lzma_rs::lzma_decompress(&mut reader, &mut pipe_writer),
-                    Codec::Xz => lzma_rs::xz_decompress(&mut reader, &mut pipe_writer),
-                }
-            });
-            Ok(Self {
-                codec,
-                decoder_handle: Some(decoder_handle),
-                pipe_reader,
-            })
+    /// Wait for the extraction to finish and move it to `target`, unless `target` already
+    /// exists.
+    pub(crate) fn finish(mut self, target: &Path) -> Result<(), Error> {
+        let mut extraction = match self.extraction.take() {
+            Some(extraction) => extraction,
+            None => return Ok(()),
+        };
+        // Closing the pipe signals the end of the archive.
+        extraction.pipe = None;
+        let mut budget = extraction
+            .handle
+            .take()
+            .unwrap()
+            .join()
+            .map_err(|_| Error::ExtractionError("extraction thread panicked".into()))??;
+        if target.is_dir() {
+            return Ok(());
         }
+        // The compression ratio can only be checked now that we know the size of the archive.
+        budget.limits.max_ratio = extraction.options.limits.max_ratio;
+        budget.archive_size = extraction.size;
+        budget.add_size(0)?;
+        let options = &extraction.options;
+        let dir = extraction.temp_target.path();
+        unpack_nested(dir, options, options.max_depth, &mut budget)?;
+        fs::rename(unpacked_root(dir, options)?, target)?;
+        Ok(())
     }
+}
 
-    impl Read for LzmaDecoder {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-            let size = self.pipe_reader.read(buf);
-            if let Some(handle) = self.decoder_handle.take_if(|h| h.is_finished()) {
-                handle
-                    .join()
-                    .map_err(|_| {
-                        std::io::Error::other(format!(
-                            "{} decompression thread panicked",
-                            self.codec
-                        ))
-                    })?
-                    .map_err(|e| {
-                        std::io::Error::other(format!("{} decompression error: {e}", self.codec))
-                    })?;
-            }
-            // handle 0-byte read edge case
-            match size {
-                Ok(0) if self.decoder_handle.is_some() => {
-                    // we read nothing, but the thread is still running, most likely a race condition, retry
-                    self.read(buf)
+impl<W: Write> Write for ExtractingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        if let Some(extraction) = &mut self.extraction {
+            extraction.size += n as u64;
+            // Any error from the extraction is returned from `finish()` instead.
+            if let Some(pipe) = &mut extraction.pipe {
+                if pipe.write_all(&buf[..n]).is_err() {
+                    extraction.pipe = None;
                 }
-                other => other,
             }
         }
+        Ok(n)
     }
 
-    #[cfg(test)]
-    mod test {
-
-        use super::*;
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
 
-        #[test]
-        #[should_panic(expected = "xz decompression error")]
-        fn test_xz_decoder_empty() {
-            let mut decoder = LzmaDecoder::new(Codec::Xz, std::io::empty()).unwrap();
-            std::io::copy(&mut decoder, &mut Vec::new()).unwrap();
+impl Drop for Extraction {
+    fn drop(&mut self) {
+        // Make sure the thread is done with the temp directory before it's removed.
+        self.pipe = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
+    }
+}
 
-        #[test]
-        #[should_panic(expected = "xz decompression error")]
-        fn test_xz_decoder_bad() {
-            let bad: &[u8] = &[0x42u8; 1024];
-            let mut decoder = LzmaDecoder::new(Codec::Xz, bad).unwrap();
-            std::io::copy(&mut decoder, &mut Vec::new()).unwrap();
-        }
+/// Unpack a tar-based archive as it's read from `reader`. The limit on the compression
+/// ratio is left to the caller, since the size of the archive isn't known up front.
+fn unpack_tar_stream<R: Read + Send + 'static>(
+    mut reader: R,
+    dst: &Path,
+    format: Option<ArchiveFormat>,
+    options: &UnpackOptions,
+) -> Result<Budget, Error> {
+    // This is enough to detect the format, even of an uncompressed tar archive.
+    let mut head = Vec::new();
+    (&mut reader).take(512).read_to_end(&mut head)?;
+    let format = format
+        .or_else(|| ArchiveFormat::detect_tar(&head))
+        .ok_or_else(|| Error::ExtractionError("not a tar-based archive".into()))?;
+    let reader = format.tar_decoder(io::Cursor::new(head).chain(reader))?;
+
+    let mut budget = Budget::new(&ExtractionLimits {
+        max_ratio: None,
+        ..options.limits
+    });
+    let mut unpacker = Unpacker::new(dst, options, &mut budget)?;
+    unpacker.unpack_tar(reader)?;
+    unpacker.finish()?;
+    Ok(budget)
+}
+
+/// The directory that should become the extraction directory after unpacking into `dir`.
+/// This is `dir` itself, unless `unwrap_single_dir` is set and `dir` only contains
+/// a single directory.
+fn unpacked_root(dir: &Path, options: &UnpackOptions) -> Result<PathBuf, Error> {
+    if !options.unwrap_single_dir {
+        return Ok(dir.to_path_buf());
+    }
+    let mut entries = fs::read_dir(dir)?;
+    let entry = match (entries.next(), entries.next()) {
+        (Some(entry), None) => entry?,
+        _ => return Ok(dir.to_path_buf()),
+    };
+    if !entry.file_type()?.is_dir() {
+        return Ok(dir.to_path_buf());
+    }
+    // Symlinks were only checked against `dir`, so a link like `../x` could now point
+    // outside of the new root.
+    let root = entry.path();
+    let mut budget = Budget::new(&options.limits);
+    let unpacker = Unpacker::new(&root, options, &mut budget)?;
+    unpacker.check_symlinks_in(Path::new(""))?;
+    Ok(root)
+}
+
+/// A record of the files in an extraction directory, written next to it so that damage
+/// to the directory can be detected later.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ExtractionManifest {
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ManifestEntry {
+    File {
+        path: PathBuf,
+        size: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        digest: Option<String>,
+    },
+    Dir {
+        path: PathBuf,
+    },
+    Symlink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+}
+
+impl ExtractionManifest {
+    /// The path to the manifest of an extraction directory.
+    pub(crate) fn path(dir: &Path) -> PathBuf {
+        let mut path = dir.as_os_str().to_owned();
+        path.push(".manifest");
+        PathBuf::from(path)
+    }
+
+    /// Record everything in `dir`, including the digest of each file if `digests` is set.
+    pub(crate) fn from_dir(dir: &Path, digests: bool) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        Self::collect(dir, Path::new(""), digests, &mut entries)?;
+        Ok(ExtractionManifest { entries })
+    }
+
+    fn collect(
+        root: &Path,
+        dir: &Path,
+        digests: bool,
+        entries: &mut Vec<ManifestEntry>,
+    ) -> Result<(), Error> {
+        let mut dir_entries = fs::read_dir(root.join(dir))?.collect::<Result<Vec<_>, _>>()?;
+        dir_entries.sort_by_key(|entry| entry.file_name());
+        for entry in dir_entries {
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                entries.push(ManifestEntry::Symlink { path, target });
+            } else if file_type.is_dir() {
+                entries.push(ManifestEntry::Dir { path: path.clone() });
+                Self::collect(root, &path, digests, entries)?;
+            } else {
+                let digest = if digests {
+                    Some(file_digest(&entry.path())?)
+                } else {
+                    None
+                };
+                let size = entry.metadata()?.len();
+                entries.push(ManifestEntry::File { path, size, digest });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read(path: &Path) -> Result<Self, Error> {
+        let serialized = fs::read_to_string(path)?;
+        serde_json::from_str(&serialized)
+            .map_err(|e| Error::CacheCorrupted(format!("invalid manifest at {path:?}: {e}")))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        let serialized = serde_json::to_string(self).map_err(|e| {
+            Error::ExtractionError(format!("failed to serialize manifest for {path:?}: {e}"))
+        })?;
+        fs::write(path, serialized)?;
+        Ok(())
+    }
+
+    /// Check that everything in the manifest is still in `dir`, unchanged. Files that
+    /// were added since are ignored.
+    pub(crate) fn verify(&self, dir: &Path) -> Result<(), Error> {
+        for entry in &self.entries {
+            let (path, ok) = match entry {
+                ManifestEntry::File { path, size, digest } => {
+                    let full_path = dir.join(path);
+                    let ok = fs::symlink_metadata(&full_path)
+                        .is_ok_and(|meta| meta.is_file() && meta.len() == *size)
+                        && match digest {
+                            Some(digest) => file_digest(&full_path)? == *digest,
+                            None => true,
+                        };
+                    (path, ok)
+                }
+                ManifestEntry::Dir { path } => (
+                    path,
+                    fs::symlink_metadata(dir.join(path)).is_ok_and(|meta| meta.is_dir()),
+                ),
+                ManifestEntry::Symlink { path, target } => (
+                    path,
+                    fs::read_link(dir.join(path)).is_ok_and(|actual| actual == *target),
+                ),
+            };
+            if !ok {
+                return Err(Error::CacheCorrupted(format!(
+                    "{path:?} in {dir:?} is missing or was modified"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The digest of the contents of a file, like `sha256:...`.
+pub(crate) fn file_digest(path: &Path) -> Result<String, Error> {
+    let mut writer = DigestWriter::new(io::sink());
+    io::copy(&mut File::open(path)?, &mut writer)?;
+    Ok(writer.digest())
+}
+
+/// Unpack an archive directly into an existing directory.
+///
+/// Entries are unpacked one at a time, and an [`Error::UnsafeArchiveEntry`] is returned
+/// if an entry would end up outside of `dst`, either through its own path or through a link.
+pub(crate) fn unpack_archive(
+    path: &Path,
+    dst: &Path,
+    format: &ArchiveFormat,
+    options: &UnpackOptions,
+) -> Result<(), Error> {
+    let mut budget = Budget::new(&options.limits);
+    unpack_archive_with(path, dst, format, options, &mut budget)?;
+    unpack_nested(dst, options, options.max_depth, &mut budget)
+}
+
+/// Replace the archives under `dir` with directories of the same name containing
+/// their unpacked entries, going up to `depth` levels of archives deep.
+///
+/// The limits apply to everything unpacked so far, so that they can't be avoided
+/// by nesting an archive bomb.
+fn unpack_nested(
+    dir: &Path,
+    options: &UnpackOptions,
+    depth: usize,
+    budget: &mut Budget,
+) -> Result<(), Error> {
+    if depth == 0 {
+        return Ok(());
+    }
+    // Filters and the like only apply to the outermost archive.
+    let options = UnpackOptions {
+        limits: options.limits,
+        threads: options.threads,
+        metadata: options.metadata,
+        links: options.links,
+        ..Default::default()
+    };
+    // Collect the entries first since we'll be adding temp directories as we go.
+    for entry in fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()? {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            unpack_nested(&path, &options, depth, budget)?;
+        } else if file_type.is_file() {
+            let format = match ArchiveFormat::parse_from_extension(&path) {
+                Ok(format) => format,
+                Err(Error::ExtractionError(_)) => continue,
+                Err(err) => return Err(err),
+            };
+            let temp_target = tempdir_in(dir)?;
+            unpack_archive_with(&path, temp_target.path(), &format, &options, budget)?;
+            unpack_nested(temp_target.path(), &options, depth - 1, budget)?;
+            fs::remove_file(&path)?;
+            fs::rename(temp_target, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack_archive_with(
+    path: &Path,
+    dst: &Path,
+    format: &ArchiveFormat,
+    options: &UnpackOptions,
+    budget: &mut Budget,
+) -> Result<(), Error> {
+    let file = File::open(path)?;
+    budget.archive_size += file.metadata()?.len();
+    let file = ProgressReader {
+        reader: file,
+        progress: options.progress.clone(),
+    };
+    let mut unpacker = Unpacker::new(dst, options, budget)?;
+    match format {
+        ArchiveFormat::Zip => unpacker.unpack_zip(file, path)?,
+        #[cfg(feature = "sevenz")]
+        ArchiveFormat::SevenZ => unpacker.unpack_7z(file)?,
+        _ => unpacker.unpack_tar(format.tar_decoder(file)?)?,
+    };
+    unpacker.finish()
+}
+
+/// Keeps track of how much has been extracted so far to enforce the [`ExtractionLimits`].
+struct Budget {
+    limits: ExtractionLimits,
+    /// The size of the compressed archives unpacked so far.
+    archive_size: u64,
+    entries: u64,
+    size: u64,
+}
+
+impl Budget {
+    fn new(limits: &ExtractionLimits) -> Self {
+        Budget {
+            limits: *limits,
+            archive_size: 0,
+            entries: 0,
+            size: 0,
+        }
+    }
+
+    fn add_entry(&mut self) -> Result<(), Error> {
+        self.entries += 1;
+        match self.limits.max_entries {
+            Some(max_entries) if self.entries > max_entries => Err(Error::ExtractionLimitExceeded(
+                format!("archive has more than {max_entries} entries"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn add_size(&mut self, size: u64) -> Result<(), Error> {
+        self.size = self.size.saturating_add(size);
+        if let Some(max_size) = self.limits.max_size {
+            if self.size > max_size {
+                return Err(Error::ExtractionLimitExceeded(format!(
+                    "extracted size exceeds {max_size} bytes"
+                )));
+            }
+        }
+        if let Some(max_ratio) = self.limits.max_ratio {
+            if self.size as f64 > max_ratio * self.archive_size as f64 {
+                return Err(Error::ExtractionLimitExceeded(format!(
+                    "compression ratio exceeds {max_ratio}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy from `reader` to `writer`, counting the bytes as they are written so that
+    /// we can stop as soon as a limit is exceeded, regardless of any sizes the archive
+    /// claims up front.
+    fn copy<R: Read + ?Sized, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        copy_counted(reader, writer, |size| self.add_size(size))
+    }
+}
+
+/// Copy from `reader` to `writer`, passing the number of bytes to `count` before
+/// they are written so that it can stop the copy by returning an error.
+fn copy_counted<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut count: impl FnMut(u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        count(n as u64)?;
+        writer.write_all(&buf[..n])?;
+    }
+}
+
+/// Unpacks the entries of an archive into a directory while making sure that nothing
+/// is written outside of it.
+struct Unpacker<'a> {
+    /// The canonical path of the directory to unpack into.
+    root: PathBuf,
+    options: &'a UnpackOptions,
+    budget: &'a mut Budget,
+    /// The symlinks that have been created along with their targets. These are checked
+    /// again once all entries are unpacked, since later entries can change what they point to.
+    symlinks: Vec<(PathBuf, PathBuf)>,
+}
+
+impl<'a> Unpacker<'a> {
+    fn new(dst: &Path, options: &'a UnpackOptions, budget: &'a mut Budget) -> Result<Self, Error> {
+        Ok(Unpacker {
+            root: dst.canonicalize()?,
+            options,
+            budget,
+            symlinks: Vec::new(),
+        })
+    }
+
+    fn unpack_tar<R: Read>(&mut self, reader: R) -> Result<(), Error> {
+        let mut archive = tar::Archive::new(reader);
+        let metadata = self.options.metadata;
+        archive.set_preserve_permissions(metadata == MetadataPolicy::Preserve);
+        archive.set_preserve_mtime(metadata != MetadataPolicy::ReadOnly);
+        archive.set_unpack_xattrs(metadata == MetadataPolicy::Preserve);
+        let mut directories = Vec::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            self.budget.add_entry()?;
+            let path = match self.entry_dst(&entry.path()?)? {
+                Some(path) => path,
+                None => continue,
+            };
+            let entry_type = entry.header().entry_type();
+            let mut link_src = None;
+            if entry_type.is_symlink() || entry_type.is_hard_link() {
+                self.check_link_allowed(&path)?;
+                let target = entry.link_name()?.map(|t| t.into_owned()).ok_or_else(|| {
+                    Error::ExtractionError(format!("missing link target for {path:?}"))
+                })?;
+                if entry_type.is_symlink() {
+                    self.check_symlink(&path, &target)?;
+                    self.symlinks.push((path.clone(), target));
+                } else {
+                    // Hard link targets are relative to the root of the archive, so they
+                    // have to be stripped the same way.
+                    let target = self.options.strip(&entry_path(&target)?).ok_or_else(|| {
+                        Error::ExtractionError(format!(
+                            "{path:?} links to {target:?}, which isn't extracted"
+                        ))
+                    })?;
+                    link_src = Some(self.check_inside(&target)?);
+                }
+            } else {
+                self.budget.add_size(entry.size())?;
+            }
+            self.check_inside(&path)?;
+
+            let outpath = self.root.join(&path);
+            if entry_type.is_dir() {
+                // Like `tar::Archive::unpack`, we create directories last so that read-only
+                // directories don't prevent us from unpacking their contents.
+                directories.push((outpath, entry));
+                continue;
+            }
+            fs::create_dir_all(outpath.parent().unwrap())?;
+            if let Some(link_src) = link_src {
+                if self.options.links == LinkPolicy::Dereference {
+                    self.copy_file(&link_src, &outpath)?;
+                } else {
+                    fs::hard_link(link_src, outpath)?;
+                }
+            } else {
+                entry.unpack(&outpath)?;
+                if metadata == MetadataPolicy::ReadOnly && entry_type.is_file() {
+                    set_read_only(&outpath, entry.header().mode()?)?;
+                }
+            }
+            self.unpacked();
+        }
+
+        directories.sort_by(|a, b| b.0.cmp(&a.0));
+        for (outpath, mut directory) in directories {
+            fs::create_dir_all(&outpath)?;
+            // `tar` only sets the modification times of files. Set it before the permissions
+            // are applied, since the directory has to be opened for it.
+            #[cfg(unix)]
+            if metadata != MetadataPolicy::ReadOnly {
+                let mtime = Duration::from_secs(directory.header().mtime()?);
+                File::open(&outpath)?.set_modified(SystemTime::UNIX_EPOCH + mtime)?;
+            }
+            directory.unpack(&outpath)?;
+            #[cfg(unix)]
+            if metadata == MetadataPolicy::ReadOnly {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&outpath, fs::Permissions::from_mode(0o755))?;
+            }
+            self.unpacked();
+        }
+
+        Ok(())
+    }
+
+    fn unpack_zip<R: Read + Seek>(&mut self, reader: R, path: &Path) -> Result<(), Error> {
+        let mut archive =
+            zip::ZipArchive::new(reader).map_err(|e| Error::ExtractionError(e.to_string()))?;
+        #[cfg(unix)]
+        let mut modes = Vec::new();
+        // Files are written once every entry has been checked and the directories and
+        // symlinks are in place, so that they can be spread across threads.
+        let mut files = Vec::new();
+        for i in 0..archive.len() {
+            let mut file = archive
+                .by_index(i)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            self.budget.add_entry()?;
+            // Some zip tools write Windows path separators.
+            let path = match self.entry_dst(Path::new(&file.name().replace('\\', "/")))? {
+                Some(path) => path,
+                None => continue,
+            };
+
+            if file.is_symlink() {
+                self.check_link_allowed(&path)?;
+                #[cfg(unix)]
+                {
+                    self.unpack_symlink(path, &mut file)?;
+                    continue;
+                }
+            }
+
+            let outpath = self.check_inside(&path)?;
+            if file.is_dir() {
+                fs::create_dir_all(&outpath)?;
+                self.unpacked();
+            } else {
+                files.push((i, outpath.clone()));
+            }
+            #[cfg(unix)]
+            if let Some(mode) = self
+                .options
+                .metadata
+                .entry_mode(file.unix_mode(), file.is_dir())
+            {
+                let mtime = match self.options.metadata {
+                    MetadataPolicy::ReadOnly => None,
+                    _ if file.is_dir() => zip_mtime(&file),
+                    // Files get their modification time when they're written.
+                    _ => None,
+                };
+                modes.push((outpath, mode, mtime));
+            }
+        }
+
+        // A symlink unpacked after a file was checked could have changed where it ends up.
+        for (path, target) in &self.symlinks {
+            self.check_symlink(path, target)?;
+        }
+        let files = last_entry_per_path(files);
+        if self.options.threads > 1 && files.len() > 1 {
+            self.unpack_zip_files_parallel(path, &files)?;
+        } else {
+            let metadata = self.options.metadata;
+            for (i, outpath) in files {
+                let mut file = archive
+                    .by_index(i)
+                    .map_err(|e| Error::ExtractionError(e.to_string()))?;
+                write_zip_file(&mut file, &outpath, metadata, |size| {
+                    self.budget.add_size(size)
+                })?;
+                self.unpacked();
+            }
+        }
+
+        #[cfg(unix)]
+        apply_modes(modes)?;
+
+        Ok(())
+    }
+
+    /// Write the files of the zip archive at `path` from several threads at once, each
+    /// with its own handle on the archive. `files` are the indices of the entries along
+    /// with where to write them, which must have been checked already.
+    fn unpack_zip_files_parallel(
+        &mut self,
+        path: &Path,
+        files: &[(usize, PathBuf)],
+    ) -> Result<(), Error> {
+        let options = self.options;
+        let budget = Mutex::new(&mut *self.budget);
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let worker = || -> Result<(), Error> {
+            // The archive was already read once to list the entries, so the progress
+            // is reported per entry rather than for every read.
+            let mut archive = zip::ZipArchive::new(File::open(path)?)
+                .map_err(|e| Error::ExtractionError(e.to_string()))?;
+            // Stop picking up files as soon as any thread fails.
+            while !failed.load(Ordering::Relaxed) {
+                let (i, outpath) = match files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    Some(file) => file,
+                    None => break,
+                };
+                let result = archive
+                    .by_index(*i)
+                    .map_err(|e| Error::ExtractionError(e.to_string()))
+                    .and_then(|mut file| {
+                        write_zip_file(&mut file, outpath, options.metadata, |size| {
+                            budget.lock().unwrap().add_size(size)
+                        })?;
+                        Ok(file.compressed_size())
+                    });
+                match result {
+                    Ok(compressed_size) => {
+                        if let Some(progress) = &options.progress {
+                            progress.update(|progress| {
+                                progress.read(compressed_size as usize);
+                                progress.unpacked();
+                            });
+                        }
+                    }
+                    Err(err) => {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(err);
+                    }
+                }
+            }
+            Ok(())
+        };
+        std::thread::scope(|scope| {
+            let handles = (0..options.threads.min(files.len()))
+                .map(|_| scope.spawn(worker))
+                .collect::<Vec<_>>();
+            // Join every thread before returning, even if one of them failed.
+            let mut result = Ok(());
+            for handle in handles {
+                let thread_result = handle.join().unwrap_or_else(|_| {
+                    Err(Error::ExtractionError(
+                        "zip extraction thread panicked".into(),
+                    ))
+                });
+                if result.is_ok() {
+                    result = thread_result;
+                }
+            }
+            result
+        })
+    }
+
+    #[cfg(feature = "sevenz")]
+    fn unpack_7z<R: Read + Seek>(&mut self, file: R) -> Result<(), Error> {
+        let root = self.root.clone();
+        let mut modes = Vec::new();
+        let mut error = None;
+        let result = sevenz_rust::decompress_with_extract_fn(file, root, |entry, reader, _| {
+            match self.unpack_7z_entry(entry, reader, &mut modes) {
+                Ok(()) => Ok(true),
+                Err(err) => {
+                    error = Some(err);
+                    Err(sevenz_rust::Error::other("extraction aborted"))
+                }
+            }
+        });
+        if let Some(err) = error {
+            return Err(err);
+        }
+        result.map_err(|e| Error::ExtractionError(e.to_string()))?;
+        #[cfg(unix)]
+        apply_modes(modes)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "sevenz")]
+    fn unpack_7z_entry(
+        &mut self,
+        entry: &sevenz_rust::SevenZArchiveEntry,
+        reader: &mut dyn Read,
+        #[cfg_attr(not(unix), allow(unused_variables))] modes: &mut EntryModes,
+    ) -> Result<(), Error> {
+        self.budget.add_entry()?;
+        let path = match self.entry_dst(Path::new(entry.name()))? {
+            Some(path) if !entry.is_anti_item() => path,
+            _ => {
+                // Skipped entries still have to be read in full for their checksums to verify.
+                io::copy(reader, &mut io::sink())?;
+                return Ok(());
+            }
+        };
+        // Like zip archives, 7z archives made on Unix store the file mode in the upper
+        // half of the attributes, and the target of a symlink as its contents.
+        let is_symlink = entry.has_windows_attributes
+            && entry.windows_attributes & 0x8000 != 0
+            && (entry.windows_attributes >> 16) & 0o170000 == 0o120000;
+        if is_symlink {
+            self.check_link_allowed(&path)?;
+            #[cfg(unix)]
+            return self.unpack_symlink(path, reader);
+        }
+        let outpath = self.check_inside(&path)?;
+        let metadata = self.options.metadata;
+        let mode = entry.has_windows_attributes && entry.windows_attributes & 0x8000 != 0;
+        let mode = mode.then_some(entry.windows_attributes >> 16);
+        let mtime = match metadata {
+            MetadataPolicy::ReadOnly => None,
+            _ if entry.has_last_modified_date => Some(SystemTime::from(entry.last_modified_date())),
+            _ => None,
+        };
+        if entry.is_directory() {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            fs::create_dir_all(outpath.parent().unwrap())?;
+            let mut outfile = File::create(&outpath)?;
+            self.budget.copy(reader, &mut outfile)?;
+            if metadata == MetadataPolicy::ReadOnly {
+                set_read_only(&outpath, mode.unwrap_or(0))?;
+            } else if let Some(mtime) = mtime {
+                outfile.set_modified(mtime)?;
+            }
+        }
+        #[cfg(unix)]
+        if let Some(mode) = metadata.entry_mode(mode, entry.is_directory()) {
+            // Files already have their modification time.
+            let mtime = if entry.is_directory() { mtime } else { None };
+            modes.push((outpath, mode, mtime));
+        }
+        self.unpacked();
+        Ok(())
+    }
+
+    /// Unpack a symlink from a zip or 7z archive, which store the target of the link
+    /// as its contents.
+    #[cfg(unix)]
+    fn unpack_symlink<R: Read + ?Sized>(
+        &mut self,
+        path: PathBuf,
+        reader: &mut R,
+    ) -> Result<(), Error> {
+        let mut target = String::new();
+        reader.take(4096).read_to_string(&mut target)?;
+        let target = PathBuf::from(target);
+        self.check_symlink(&path, &target)?;
+        let outpath = self.check_inside(&path)?;
+        fs::create_dir_all(outpath.parent().unwrap())?;
+        std::os::unix::fs::symlink(&target, outpath)?;
+        self.symlinks.push((path, target));
+        self.unpacked();
+        Ok(())
+    }
+
+    /// Check that the link policy allows the link at `path`.
+    fn check_link_allowed(&self, path: &Path) -> Result<(), Error> {
+        if self.options.links == LinkPolicy::Reject {
+            return Err(Error::UnsafeArchiveEntry(format!(
+                "{path:?} is a link, and links are rejected"
+            )));
+        }
+        Ok(())
+    }
+
+    fn unpacked(&self) {
+        if let Some(progress) = &self.options.progress {
+            progress.update(|progress| progress.unpacked());
+        }
+    }
+
+    /// Get the path, relative to the root, to unpack an entry to. Returns `None` if the
+    /// entry should be skipped.
+    fn entry_dst(&self, name: &Path) -> Result<Option<PathBuf>, Error> {
+        let path = entry_path(name)?;
+        if path.as_os_str().is_empty() || !self.options.includes(&path) {
+            return Ok(None);
+        }
+        Ok(self.options.strip(&path))
+    }
+
+    /// Resolve a path relative to the root the same way the OS would, following any
+    /// symlinks that have been unpacked so far.
+    fn resolve(&self, path: &Path) -> Result<PathBuf, Error> {
+        let parent_dir = OsString::from("..");
+        let mut resolved = self.root.clone();
+        let mut pending = path_components(path);
+        let mut followed = 0;
+        while let Some(component) = pending.pop() {
+            if component == parent_dir {
+                // Going above the root and back in would depend on the name of the root,
+                // which changes when it's moved into place.
+                if resolved == self.root {
+                    return Err(Error::UnsafeArchiveEntry(format!(
+                        "{path:?} is outside of the extraction directory"
+                    )));
+                }
+                resolved.pop();
+                continue;
+            }
+            resolved.push(&component);
+            if let Ok(target) = fs::read_link(&resolved) {
+                followed += 1;
+                if followed > MAX_SYMLINKS {
+                    return Err(Error::UnsafeArchiveEntry(format!(
+                        "too many levels of symbolic links in {path:?}"
+                    )));
+                }
+                if target.has_root() {
+                    return Ok(target);
+                }
+                resolved.pop();
+                pending.extend(path_components(&target));
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Check that a path relative to the root resolves to a location inside of the root,
+    /// returning the resolved path.
+    fn check_inside(&self, path: &Path) -> Result<PathBuf, Error> {
+        let resolved = self.resolve(path)?;
+        if resolved.starts_with(&self.root) {
+            Ok(resolved)
+        } else {
+            Err(Error::UnsafeArchiveEntry(format!(
+                "{path:?} is outside of the extraction directory"
+            )))
+        }
+    }
+
+    /// Check that a symlink at `path` pointing to `target` stays inside of the root.
+    fn check_symlink(&self, path: &Path, target: &Path) -> Result<(), Error> {
+        if target.has_root() {
+            return Err(Error::UnsafeArchiveEntry(format!(
+                "{path:?} links to absolute path {target:?}"
+            )));
+        }
+        let link_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if self.check_inside(&link_dir.join(target)).is_err() {
+            return Err(Error::UnsafeArchiveEntry(format!(
+                "{path:?} links to {target:?}, outside of the extraction directory"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check all of the symlinks under a directory relative to the root.
+    fn check_symlinks_in(&self, dir: &Path) -> Result<(), Error> {
+        for entry in fs::read_dir(self.root.join(dir))? {
+            let entry = entry?;
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                self.check_symlink(&path, &fs::read_link(entry.path())?)?;
+            } else if file_type.is_dir() {
+                self.check_symlinks_in(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        for (path, target) in &self.symlinks {
+            self.check_symlink(path, target)?;
+        }
+        if self.options.links == LinkPolicy::Dereference {
+            self.dereference_in(Path::new(""))?;
+        }
+        Ok(())
+    }
+
+    /// Replace all of the symlinks under a directory relative to the root with copies
+    /// of what they point to. They must have all been checked already.
+    fn dereference_in(&mut self, dir: &Path) -> Result<(), Error> {
+        for entry in fs::read_dir(self.root.join(dir))?.collect::<Result<Vec<_>, _>>()? {
+            let path = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                let target = self.check_inside(&path)?;
+                if !target.exists() {
+                    return Err(Error::ExtractionError(format!(
+                        "{path:?} links to {:?}, which doesn't exist",
+                        fs::read_link(entry.path())?
+                    )));
+                }
+                fs::remove_file(entry.path())?;
+                // Copying a directory into itself would never end.
+                let mut ancestors = vec![entry.path()];
+                self.copy_tree(&target, &entry.path(), &mut ancestors)?;
+            } else if file_type.is_dir() {
+                self.dereference_in(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a file or directory, following any symlinks in it. `ancestors` are the
+    /// directories being copied from and to, to catch symlink loops.
+    fn copy_tree(
+        &mut self,
+        src: &Path,
+        dst: &Path,
+        ancestors: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let src = src.canonicalize()?;
+        if !src.starts_with(&self.root) {
+            return Err(Error::UnsafeArchiveEntry(format!(
+                "{src:?} is outside of the extraction directory"
+            )));
+        }
+        if ancestors.contains(&src) {
+            return Err(Error::UnsafeArchiveEntry(format!(
+                "{:?} is part of a symlink loop",
+                src.strip_prefix(&self.root).unwrap_or(&src)
+            )));
+        }
+        // Copies count towards the limits, so that links can't be used to get around them.
+        self.budget.add_entry()?;
+        if src.is_dir() {
+            fs::create_dir(dst)?;
+            ancestors.push(src.clone());
+            ancestors.push(dst.to_path_buf());
+            for entry in fs::read_dir(&src)?.collect::<Result<Vec<_>, _>>()? {
+                self.copy_tree(&entry.path(), &dst.join(entry.file_name()), ancestors)?;
+            }
+            ancestors.truncate(ancestors.len() - 2);
+            fs::set_permissions(dst, src.metadata()?.permissions())?;
+        } else {
+            self.copy_file(&src, dst)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a single file along with its permissions and, unless the metadata policy
+    /// says otherwise, its modification time.
+    fn copy_file(&mut self, src: &Path, dst: &Path) -> Result<(), Error> {
+        let mut reader = File::open(src)?;
+        let metadata = reader.metadata()?;
+        let mut writer = File::create(dst)?;
+        self.budget.copy(&mut reader, &mut writer)?;
+        if self.options.metadata != MetadataPolicy::ReadOnly {
+            writer.set_modified(metadata.modified()?)?;
+        }
+        fs::set_permissions(dst, metadata.permissions())?;
+        Ok(())
+    }
+}
+
+/// The permissions, and for directories the modification times, to give extracted zip
+/// or 7z entries once all of them have been unpacked.
+type EntryModes = Vec<(PathBuf, u32, Option<SystemTime>)>;
+
+/// Set the permissions and modification times of extracted zip or 7z entries. Children go
+/// first so that a read-only directory doesn't get in the way.
+#[cfg(unix)]
+fn apply_modes(mut modes: EntryModes) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    modes.sort_by(|a, b| b.0.cmp(&a.0));
+    for (path, mode, mtime) in modes {
+        if let Some(mtime) = mtime {
+            File::open(&path)?.set_modified(mtime)?;
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Keep only the last of the zip `files` written to each path, since entries with names like
+/// `data.txt` and `./data.txt` end up in the same place. This leaves the same file behind as
+/// writing all of them would, without creating a file twice, which fails once the first copy
+/// is read-only and races when done from different threads.
+fn last_entry_per_path(files: Vec<(usize, PathBuf)>) -> Vec<(usize, PathBuf)> {
+    let last: HashMap<&Path, usize> = files
+        .iter()
+        .enumerate()
+        .map(|(j, (_, outpath))| (outpath.as_path(), j))
+        .collect();
+    let keep: Vec<bool> = files
+        .iter()
+        .enumerate()
+        .map(|(j, (_, outpath))| last[outpath.as_path()] == j)
+        .collect();
+    files
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(file, keep)| keep.then_some(file))
+        .collect()
+}
+
+/// Write a file from a zip archive to `outpath`, which must have been checked already,
+/// passing the number of bytes written so far to `count`.
+fn write_zip_file<R: Read>(
+    file: &mut zip::read::ZipFile<'_, R>,
+    outpath: &Path,
+    metadata: MetadataPolicy,
+    count: impl FnMut(u64) -> Result<(), Error>,
+) -> Result<(), Error> {
+    fs::create_dir_all(outpath.parent().unwrap())?;
+    let mut outfile = File::create(outpath)?;
+    copy_counted(file, &mut outfile, count)?;
+    if metadata == MetadataPolicy::ReadOnly {
+        set_read_only(outpath, file.unix_mode().unwrap_or(0))?;
+    } else if let Some(mtime) = zip_mtime(file) {
+        outfile.set_modified(mtime)?;
+    }
+    Ok(())
+}
+
+/// The modification time of a zip entry. The extended timestamp field is used if the
+/// entry has one, otherwise the MS-DOS timestamp, which has no time zone and is taken
+/// to be UTC like the `zip` crate does.
+fn zip_mtime<R: Read>(file: &zip::read::ZipFile<'_, R>) -> Option<SystemTime> {
+    let extended = file.extra_data_fields().find_map(|field| match field {
+        zip::ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+        _ => None,
+    });
+    let secs = match extended {
+        Some(secs) => u64::from(secs),
+        None => {
+            let time = file.last_modified()?;
+            let (year, month, day) = (
+                u64::from(time.year()),
+                u64::from(time.month()),
+                u64::from(time.day()),
+            );
+            if !(1..=12).contains(&month) || day == 0 {
+                return None;
+            }
+            // Days since the epoch of a date in the proleptic Gregorian calendar, with
+            // years starting in March so that the leap day comes last.
+            let (year, month) = if month <= 2 {
+                (year - 1, month + 9)
+            } else {
+                (year, month - 3)
+            };
+            let day_of_year = (153 * month + 2) / 5 + day - 1;
+            let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719_468;
+            days * 86_400
+                + u64::from(time.hour()) * 3_600
+                + u64::from(time.minute()) * 60
+                + u64::from(time.second())
+        }
+    };
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Make an unpacked file read-only, keeping its execute bits from `mode` on Unix.
+fn set_read_only(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(if mode & 0o111 != 0 { 0o555 } else { 0o444 })
+    };
+    #[cfg(not(unix))]
+    let permissions = {
+        let _ = mode;
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(true);
+        permissions
+    };
+    fs::set_permissions(path, permissions)
+}
+
+/// Normalize the path of an archive entry, rejecting absolute paths and paths that
+/// would escape the extraction directory through `..`.
+fn entry_path(path: &Path) -> Result<PathBuf, Error> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(Error::UnsafeArchiveEntry(format!(
+                        "{path:?} is outside of the extraction directory"
+                    )));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(Error::UnsafeArchiveEntry(format!(
+                    "{path:?} is an absolute path"
+                )));
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+/// The components of a relative path in reverse order, so they can be used as a stack.
+fn path_components(path: &Path) -> Vec<OsString> {
+    path.components()
+        .rev()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Create a zstd decoder that can handle archives compressed with long windows
+/// (e.g. with `zstd --long=31`).
+///
+/// The decoder reads all concatenated frames, and memory use is bounded by the window size
+/// of the archive rather than by the size of the archive.
+#[cfg(feature = "zstd")]
+fn zstd_decoder<R: Read>(
+    reader: R,
+) -> std::io::Result<zstd::Decoder<'static, std::io::BufReader<R>>> {
+    let mut decoder = zstd::Decoder::new(reader)?;
+    // The maximum window size supported by zstd is 2^31 bytes.
+    decoder.window_log_max(31)?;
+    Ok(decoder)
+}
+
+#[cfg(any(feature = "lzma", feature = "xz-native"))]
+mod lzma {
+    use std::io::Read;
+    #[cfg(feature = "lzma")]
+    use std::thread::JoinHandle;
+
+    #[derive(Clone, Copy)]
+    pub(super) enum Codec {
+        Lzma,
+        Xz,
+    }
+
+    #[cfg(feature = "lzma")]
+    impl std::fmt::Display for Codec {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Codec::Lzma => write!(f, "lzma"),
+                Codec::Xz => write!(f, "xz"),
+            }
+        }
+    }
+
+    /// Wrap `reader` with a decoder for `codec`, using liblzma when the `xz-native`
+    /// feature is enabled and falling back to `lzma-rs` otherwise.
+    #[cfg(feature = "xz-native")]
+    pub(super) fn decoder<R: Read + Send + 'static>(
+        codec: Codec,
+        reader: R,
+    ) -> std::io::Result<Box<dyn Read + Send>> {
+        let stream = match codec {
+            Codec::Lzma => liblzma::stream::Stream::new_lzma_decoder(u64::MAX)?,
+            // Blocks of an xz stream are decoded in parallel, as long as the stream was
+            // compressed into several blocks (e.g. with `xz -T0`).
+            Codec::Xz => liblzma::stream::MtStreamBuilder::new()
+                .threads(std::thread::available_parallelism().map_or(1, |n| n.get() as u32))
+                .memlimit_stop(u64::MAX)
+                .decoder()?,
+        };
+        Ok(Box::new(liblzma::read::XzDecoder::new_stream(
+            reader, stream,
+        )))
+    }
+
+    /// Wrap `reader` with a decoder for `codec`, using liblzma when the `xz-native`
+    /// feature is enabled and falling back to `lzma-rs` otherwise.
+    #[cfg(not(feature = "xz-native"))]
+    pub(super) fn decoder<R: Read + Send + 'static>(
+        codec: Codec,
+        reader: R,
+    ) -> std::io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(LzmaDecoder::new(codec, reader)?))
+    }
+
+    /// Decodes with `lzma-rs` on a separate thread, since it only works with writers.
+    #[cfg(feature = "lzma")]
+    #[cfg_attr(all(feature = "xz-native", not(test)), allow(dead_code))]
+    pub(super) struct LzmaDecoder {
+        codec: Codec,
+        decoder_handle: Option<JoinHandle<Result<(), lzma_rs::error::Error>>>,
+        pipe_reader: std::io::PipeReader,
+    }
+
+    #[cfg(feature = "lzma")]
+    #[cfg_attr(all(feature = "xz-native", not(test)), allow(dead_code))]
+    impl LzmaDecoder {
+        pub(super) fn new<R: Read + Send + 'static>(
+            codec: Codec,
+            reader: R,
+        ) -> std::io::Result<Self> {
+            let (pipe_reader, mut pipe_writer) = std::io::pipe()?;
+            let decoder_handle = std::thread::spawn(move || {
+                let mut reader = std::io::BufReader::new(reader);
+                match codec {
+                    Codec::Lzma => lzma_rs::lzma_decompress(&mut reader, &mut pipe_writer),
+                    Codec::Xz => lzma_rs::xz_decompress(&mut reader, &mut pipe_writer),
+                }
+            });
+            Ok(Self {
+                codec,
+                decoder_handle: Some(decoder_handle),
+                pipe_reader,
+            })
+        }
+    }
+
+    #[cfg(feature = "lzma")]
+    impl Read for LzmaDecoder {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+            let size = self.pipe_reader.read(buf)?;
+            // The pipe is only closed once the decoder thread is done, so that's when we
+            // find out whether decoding succeeded. An empty `buf` also reads nothing.
+            if size == 0 && !buf.is_empty() {
+                if let Some(handle) = self.decoder_handle.take() {
+                    handle
+                        .join()
+                        .map_err(|_| {
+                            std::io::Error::other(format!(
+                                "{} decompression thread panicked",
+                                self.codec
+                            ))
+                        })?
+                        .map_err(|e| {
+                            std::io::Error::other(format!(
+                                "{} decompression error: {e}",
+                                self.codec
+                            ))
+                        })?;
+                }
+            }
+            Ok(size)
+        }
+    }
+
+    #[cfg(all(test, feature = "lzma"))]
+    mod test {
+
+        use super::*;
+
+        #[test]
+        #[should_panic(expected = "xz decompression error")]
+        fn test_xz_decoder_empty() {
+            let mut decoder = LzmaDecoder::new(Codec::Xz, std::io::empty()).unwrap();
+            std::io::copy(&mut decoder, &mut Vec::new()).unwrap();
+        }
+
+        #[test]
+        #[should_panic(expected = "xz decompression error")]
+        fn test_xz_decoder_bad() {
+            let bad: &[u8] = &[0x42u8; 1024];
+            let mut decoder = LzmaDecoder::new(Codec::Xz, bad).unwrap();
+            std::io::copy(&mut decoder, &mut Vec::new()).unwrap();
+        }
 
         #[test]
         #[should_panic(expected = "lzma decompression error")]
@@ -239,5 +2212,1187 @@ mod lzma {
             let mut decoder = LzmaDecoder::new(Codec::Lzma, bad).unwrap();
             std::io::copy(&mut decoder, &mut Vec::new()).unwrap();
         }
+
+        #[test]
+        fn test_lzma_decoder_empty_buf() {
+            let mut decoder = LzmaDecoder::new(Codec::Xz, std::io::empty()).unwrap();
+            assert_eq!(decoder.read(&mut []).unwrap(), 0);
+        }
+    }
+
+    #[cfg(all(test, feature = "xz-native"))]
+    mod native_test {
+        use super::*;
+
+        #[test]
+        fn test_native_decoder_bad() {
+            for codec in [Codec::Xz, Codec::Lzma] {
+                let bad: &[u8] = &[0x42u8; 1024];
+                let mut decoder = decoder(codec, bad).unwrap();
+                assert!(std::io::copy(&mut decoder, &mut Vec::new()).is_err());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{tempdir, TempDir};
+
+    /// Build a tar archive from `(path, entry type, link target or contents)` entries,
+    /// writing the raw header fields so that unsafe paths aren't rejected up front.
+    fn build_tar(path: &Path, entries: &[(&str, tar::EntryType, &str)]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, entry_type, value) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o755);
+            let data = if entry_type.is_symlink() || entry_type.is_hard_link() {
+                header.as_old_mut().linkname[..value.len()].copy_from_slice(value.as_bytes());
+                &b""[..]
+            } else {
+                value.as_bytes()
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Unpack a tar archive with the given entries into the `dst` subdirectory of
+    /// the returned temp directory.
+    fn unpack_tar(entries: &[(&str, tar::EntryType, &str)]) -> Result<TempDir, Error> {
+        unpack_tar_with(entries, &UnpackOptions::default())
+    }
+
+    fn unpack_tar_with(
+        entries: &[(&str, tar::EntryType, &str)],
+        options: &UnpackOptions,
+    ) -> Result<TempDir, Error> {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.tar");
+        build_tar(&archive, entries);
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        unpack_archive(&archive, &dst, &ArchiveFormat::Tar, options)?;
+        Ok(dir)
+    }
+
+    /// Extract a tar archive with the given entries to the `dst` subdirectory of
+    /// the returned temp directory.
+    fn extract_tar(
+        entries: &[(&str, tar::EntryType, &str)],
+        options: &UnpackOptions,
+    ) -> Result<TempDir, Error> {
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.tar");
+        build_tar(&archive, entries);
+        extract_archive(
+            &archive,
+            &dir.path().join("dst"),
+            &ArchiveFormat::Tar,
+            options,
+        )?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_entry_path() {
+        assert_eq!(
+            entry_path(Path::new("./a/../b/c")).unwrap(),
+            PathBuf::from("b/c")
+        );
+        assert!(matches!(
+            entry_path(Path::new("a/../../b")),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        assert!(matches!(
+            entry_path(Path::new("/etc/passwd")),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+    }
+
+    #[test]
+    fn test_tar_rejects_unsafe_paths() {
+        use tar::EntryType::Regular;
+        assert!(matches!(
+            unpack_tar(&[("../evil.txt", Regular, "evil")]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        assert!(matches!(
+            unpack_tar(&[("/tmp/evil.txt", Regular, "evil")]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tar_rejects_escaping_links() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+        assert!(matches!(
+            unpack_tar(&[("link", Symlink, "../outside")]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        assert!(matches!(
+            unpack_tar(&[("link", Symlink, "/etc")]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        assert!(matches!(
+            unpack_tar(&[("link", Link, "/etc/passwd")]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        // This looks harmless lexically, but 'up' points two levels deep,
+        // so 'up/../../..' is the parent of the extraction directory.
+        assert!(matches!(
+            unpack_tar(&[
+                ("a/b/", Directory, ""),
+                ("up", Symlink, "a/b"),
+                ("escape", Symlink, "up/../../.."),
+            ]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        // Writing through a link to a directory that's fine on its own
+        // can't be used to escape either.
+        assert!(matches!(
+            unpack_tar(&[
+                ("a/b/", Directory, ""),
+                ("up", Symlink, "a/b"),
+                ("up/../../../evil.txt", Regular, "evil"),
+            ]),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+
+        // Links that stay inside are fine.
+        let dir = unpack_tar(&[
+            ("a/b/file.txt", Regular, "hello"),
+            ("a/link", Symlink, "b/file.txt"),
+            ("hard", Link, "a/b/file.txt"),
+        ])
+        .unwrap();
+        let dst = dir.path().join("dst");
+        assert_eq!(fs::read_to_string(dst.join("a/link")).unwrap(), "hello");
+        assert_eq!(fs::read_to_string(dst.join("hard")).unwrap(), "hello");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_strip_components() {
+        use tar::EntryType::{Directory, Link, Regular, Symlink};
+        let options = UnpackOptions {
+            strip_components: 1,
+            ..Default::default()
+        };
+        let dir = unpack_tar_with(
+            &[
+                ("project/", Directory, ""),
+                ("project/a.txt", Regular, "a"),
+                ("project/sub/b.txt", Regular, "b"),
+                ("project/link", Symlink, "sub/b.txt"),
+                ("project/hard", Link, "project/a.txt"),
+                ("top.txt", Regular, "top"),
+            ],
+            &options,
+        )
+        .unwrap();
+        let dst = dir.path().join("dst");
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("sub/b.txt")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dst.join("link")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(dst.join("hard")).unwrap(), "a");
+        assert!(!dst.join("top.txt").exists());
+        assert!(!dst.join("project").exists());
+
+        // A link to something that's stripped away.
+        assert!(matches!(
+            unpack_tar_with(
+                &[
+                    ("project/link", Symlink, "../top.txt"),
+                    ("top.txt", Regular, "")
+                ],
+                &options
+            ),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+        assert!(matches!(
+            unpack_tar_with(
+                &[("top.txt", Regular, ""), ("project/hard", Link, "top.txt")],
+                &options
+            ),
+            Err(Error::ExtractionError(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unwrap_single_dir() {
+        use tar::EntryType::{Regular, Symlink};
+        let options = UnpackOptions {
+            unwrap_single_dir: true,
+            ..Default::default()
+        };
+        let dir = extract_tar(
+            &[
+                ("project-1.2.3/a.txt", Regular, "a"),
+                ("project-1.2.3/sub/b.txt", Regular, "b"),
+            ],
+            &options,
+        )
+        .unwrap();
+        let dst = dir.path().join("dst");
+        assert_eq!(fs::read_to_string(dst.join("a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dst.join("sub/b.txt")).unwrap(), "b");
+
+        // Nothing to unwrap.
+        let dir = extract_tar(
+            &[("project/a.txt", Regular, "a"), ("b.txt", Regular, "b")],
+            &options,
+        )
+        .unwrap();
+        assert!(dir.path().join("dst/project/a.txt").is_file());
+        let dir = extract_tar(&[("a.txt", Regular, "a")], &options).unwrap();
+        assert!(dir.path().join("dst/a.txt").is_file());
+
+        // This link is fine until 'project' is unwrapped.
+        assert!(matches!(
+            extract_tar(
+                &[
+                    ("project/a.txt", Regular, "a"),
+                    ("project/link", Symlink, "../project/a.txt"),
+                ],
+                &options
+            ),
+            Err(Error::UnsafeArchiveEntry(_))
+        ));
+    }
+
+    #[test]
+    fn test_zip_rejects_unsafe_paths() {
+        use zip::write::SimpleFileOptions;
+
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        writer
+            .start_file("../evil.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"evil").unwrap();
+        writer.finish().unwrap();
+
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let result = unpack_archive(
+            &archive,
+            &dst,
+            &ArchiveFormat::Zip,
+            &UnpackOptions::default(),
+        );
+        assert!(matches!(result, Err(Error::UnsafeArchiveEntry(_))));
+        assert!(!dir.path().join("evil.txt").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_zip_rejects_escaping_symlinks() {
+        use zip::write::SimpleFileOptions;
+
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        writer
+            .add_symlink("link", "../..", SimpleFileOptions::default())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let result = unpack_archive(
+            &archive,
+            &dst,
+            &ArchiveFormat::Zip,
+            &UnpackOptions::default(),
+        );
+        assert!(matches!(result, Err(Error::UnsafeArchiveEntry(_))));
+    }
+
+    fn unpack_fixture(limits: &ExtractionLimits) -> Result<(), Error> {
+        let dir = tempdir().unwrap();
+        unpack_archive(
+            Path::new("test_fixtures/utf-8_sample/archives/utf-8.tar.gz"),
+            dir.path(),
+            &ArchiveFormat::TarGz,
+            &UnpackOptions {
+                limits: *limits,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_extraction_limits() {
+        assert!(unpack_fixture(&ExtractionLimits::default()).is_ok());
+        assert!(unpack_fixture(&ExtractionLimits {
+            max_size: Some(1 << 20),
+            max_entries: Some(10),
+            max_ratio: Some(100.0),
+        })
+        .is_ok());
+
+        for limits in [
+            ExtractionLimits {
+                max_size: Some(10),
+                ..Default::default()
+            },
+            ExtractionLimits {
+                max_entries: Some(1),
+                ..Default::default()
+            },
+            ExtractionLimits {
+                max_ratio: Some(1.0),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                unpack_fixture(&limits),
+                Err(Error::ExtractionLimitExceeded(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_zip_bomb() {
+        use zip::write::SimpleFileOptions;
+
+        // A megabyte of zeros compresses down to about a kilobyte.
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        writer
+            .start_file("zeros", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(&vec![0; 1 << 20]).unwrap();
+        writer.finish().unwrap();
+
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let options = UnpackOptions {
+            limits: ExtractionLimits {
+                max_ratio: Some(100.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = unpack_archive(&archive, &dst, &ArchiveFormat::Zip, &options);
+        assert!(matches!(result, Err(Error::ExtractionLimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_parallel_zip() {
+        use zip::write::SimpleFileOptions;
+
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        writer
+            .add_directory("shards/", SimpleFileOptions::default())
+            .unwrap();
+        for i in 0..200 {
+            writer
+                .start_file(format!("shards/{i}.bin"), SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(&vec![i as u8; 1000 + i]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let unpack = |threads, limits: Option<ExtractionLimits>| {
+            let dst = dir
+                .path()
+                .join(format!("dst-{threads}-{}", limits.is_some()));
+            fs::create_dir(&dst).unwrap();
+            let options = UnpackOptions {
+                limits: limits.unwrap_or_default(),
+                threads,
+                ..Default::default()
+            };
+            unpack_archive(&archive, &dst, &ArchiveFormat::Zip, &options).map(|_| dst)
+        };
+        let sequential = unpack(1, None).unwrap();
+        let parallel = unpack(8, None).unwrap();
+        for i in 0..200 {
+            let name = format!("shards/{i}.bin");
+            let contents = fs::read(parallel.join(&name)).unwrap();
+            assert_eq!(contents, fs::read(sequential.join(&name)).unwrap());
+            assert_eq!(contents, vec![i as u8; 1000 + i]);
+        }
+
+        // The limits apply to the files written by all of the threads together.
+        let limits = ExtractionLimits {
+            max_size: Some(100_000),
+            ..Default::default()
+        };
+        let result = unpack(8, Some(limits));
+        assert!(matches!(result, Err(Error::ExtractionLimitExceeded(_))));
+    }
+
+    #[test]
+    fn test_extract_layers_limits() {
+        let dir = tempdir().unwrap();
+        let contents = "x".repeat(600);
+        let archive_layers: Vec<PathBuf> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(format!("{name}.tar"));
+                let mut builder = tar::Builder::new(File::create(&path).unwrap());
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                builder
+                    .append_data(&mut header, format!("{name}.txt"), contents.as_bytes())
+                    .unwrap();
+                builder.finish().unwrap();
+                path
+            })
+            .collect();
+        let file_layers: Vec<PathBuf> = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(format!("{name}.txt"));
+                fs::write(&path, &contents).unwrap();
+                path
+            })
+            .collect();
+
+        let extract = |layers: &[PathBuf], limits| {
+            let target = dir.path().join("target");
+            let _ = fs::remove_dir_all(&target);
+            let options = UnpackOptions {
+                limits,
+                ..Default::default()
+            };
+            extract_layers(layers, &target, &options)
+        };
+        let max_size = ExtractionLimits {
+            max_size: Some(1000),
+            ..Default::default()
+        };
+        let max_entries = ExtractionLimits {
+            max_entries: Some(1),
+            ..Default::default()
+        };
+        for layers in [&archive_layers, &file_layers] {
+            // Each layer fits under the limits on its own, but not together with the other.
+            for limits in [max_size, max_entries] {
+                extract(&layers[..1], limits).unwrap();
+                assert!(matches!(
+                    extract(layers, limits),
+                    Err(Error::ExtractionLimitExceeded(_))
+                ));
+            }
+            extract(layers, ExtractionLimits::default()).unwrap();
+            let target = dir.path().join("target");
+            assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), contents);
+            assert!(!target.join("a.tar").exists());
+        }
+    }
+
+    #[test]
+    fn test_parallel_zip_duplicate_entries() {
+        use zip::write::SimpleFileOptions;
+
+        // Pairs of entries that are written to the same file, where the last one wins.
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        for i in 0..50 {
+            for (name, fill) in [(format!("{i}.bin"), 0u8), (format!("./{i}.bin"), 1u8)] {
+                writer
+                    .start_file(name, SimpleFileOptions::default())
+                    .unwrap();
+                writer.write_all(&vec![fill; 100_000]).unwrap();
+            }
+        }
+        writer.finish().unwrap();
+
+        for (threads, metadata) in [
+            (1, MetadataPolicy::Default),
+            (8, MetadataPolicy::Default),
+            // The first copy would already be read-only when the second one is written.
+            (1, MetadataPolicy::ReadOnly),
+            (8, MetadataPolicy::ReadOnly),
+        ] {
+            let dst = dir
+                .path()
+                .join(format!("dst-{threads}-{}", metadata.name()));
+            fs::create_dir(&dst).unwrap();
+            let options = UnpackOptions {
+                threads,
+                metadata,
+                ..Default::default()
+            };
+            unpack_archive(&archive, &dst, &ArchiveFormat::Zip, &options).unwrap();
+            for i in 0..50 {
+                let contents = fs::read(dst.join(format!("{i}.bin"))).unwrap();
+                assert_eq!(contents, vec![1u8; 100_000]);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_metadata_policy() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // All of the fixtures have the same entries, with all of their times set to this.
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let mut fixtures = vec!["metadata.tar", "metadata.zip"];
+        if cfg!(feature = "sevenz") {
+            fixtures.push("metadata.7z");
+        }
+        for fixture in fixtures {
+            let path = Path::new("test_fixtures/metadata").join(fixture);
+            let unpack = |metadata| {
+                let dir = tempdir().unwrap();
+                let options = UnpackOptions {
+                    metadata,
+                    ..Default::default()
+                };
+                let format = ArchiveFormat::parse_from_extension(&path).unwrap();
+                unpack_archive(&path, dir.path(), &format, &options).unwrap();
+                dir
+            };
+            let mode = |dir: &TempDir, name: &str| {
+                fs::metadata(dir.path().join(name))
+                    .unwrap()
+                    .permissions()
+                    .mode()
+                    & 0o7777
+            };
+            let modified = |dir: &TempDir, name: &str| {
+                fs::metadata(dir.path().join(name))
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+            };
+            let origin = |dir: &TempDir| xattr::get(dir.path().join("data.txt"), "user.origin");
+
+            let dir = unpack(MetadataPolicy::Default);
+            assert_eq!(mode(&dir, "bin/run.sh"), 0o755, "{}", fixture);
+            assert_eq!(mode(&dir, "bin/setuid"), 0o755, "{}", fixture);
+            assert_eq!(mode(&dir, "data.txt"), 0o640, "{}", fixture);
+            for name in ["bin", "bin/run.sh", "data.txt"] {
+                assert_eq!(modified(&dir, name), mtime, "{} {}", fixture, name);
+            }
+            assert_eq!(origin(&dir).unwrap(), None, "{}", fixture);
+
+            let dir = unpack(MetadataPolicy::Preserve);
+            assert_eq!(mode(&dir, "bin/run.sh"), 0o755, "{}", fixture);
+            assert_eq!(mode(&dir, "bin/setuid"), 0o4755, "{}", fixture);
+            assert_eq!(mode(&dir, "data.txt"), 0o640, "{}", fixture);
+            for name in ["bin", "bin/run.sh", "data.txt"] {
+                assert_eq!(modified(&dir, name), mtime, "{} {}", fixture, name);
+            }
+            // Only tar archives have extended attributes, and not every file system
+            // supports them.
+            if fixture.ends_with(".tar") && origin(&dir).is_ok() {
+                assert_eq!(origin(&dir).unwrap().as_deref(), Some(&b"fixture"[..]));
+            }
+
+            let dir = unpack(MetadataPolicy::ReadOnly);
+            assert_eq!(mode(&dir, "bin"), 0o755, "{}", fixture);
+            assert_eq!(mode(&dir, "bin/run.sh"), 0o555, "{}", fixture);
+            assert_eq!(mode(&dir, "bin/setuid"), 0o555, "{}", fixture);
+            assert_eq!(mode(&dir, "data.txt"), 0o444, "{}", fixture);
+            assert_ne!(modified(&dir, "data.txt"), mtime, "{}", fixture);
+            assert_eq!(origin(&dir).unwrap(), None, "{}", fixture);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_metadata_policy_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        for (name, mode, data) in [("locked/", 0o311, &b""[..]), ("locked/a.txt", 0o644, b"a")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(name).unwrap();
+            header.set_entry_type(if data.is_empty() {
+                tar::EntryType::Directory
+            } else {
+                tar::EntryType::Regular
+            });
+            header.set_mode(mode);
+            header.set_mtime(1_000_000_000);
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.finish().unwrap();
+
+        let dst = dir.path().join("dst");
+        fs::create_dir(&dst).unwrap();
+        let options = UnpackOptions {
+            metadata: MetadataPolicy::Preserve,
+            ..Default::default()
+        };
+        unpack_archive(&archive, &dst, &ArchiveFormat::Tar, &options).unwrap();
+
+        let locked = dst.join("locked");
+        let metadata = fs::metadata(&locked).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o311);
+        assert_eq!(
+            metadata.modified().unwrap(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+        // Let the temp directory be cleaned up.
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(fs::read(locked.join("a.txt")).unwrap(), b"a");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_link_policy() {
+        use std::os::unix::fs::MetadataExt;
+        use tar::EntryType;
+
+        let entries = [
+            ("dir/file.txt", EntryType::Regular, "contents"),
+            ("file-link", EntryType::Symlink, "dir/file.txt"),
+            ("dir-link", EntryType::Symlink, "dir"),
+            ("link-link", EntryType::Symlink, "dir-link/file.txt"),
+            ("hard-link", EntryType::Link, "dir/file.txt"),
+        ];
+        let unpack = |entries: &[(&str, EntryType, &str)], links| {
+            let options = UnpackOptions {
+                links,
+                ..Default::default()
+            };
+            unpack_tar_with(entries, &options)
+        };
+        let is_symlink = |path: PathBuf| fs::symlink_metadata(path).unwrap().is_symlink();
+
+        let dir = unpack(&entries, LinkPolicy::Keep).unwrap();
+        let dst = dir.path().join("dst");
+        for name in ["file-link", "dir-link", "link-link"] {
+            assert!(is_symlink(dst.join(name)), "{}", name);
+        }
+        let ino = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        assert_eq!(ino(dst.join("hard-link")), ino(dst.join("dir/file.txt")));
+
+        let dir = unpack(&entries, LinkPolicy::Dereference).unwrap();
+        let dst = dir.path().join("dst");
+        for name in ["file-link", "dir-link", "link-link", "hard-link"] {
+            assert!(!is_symlink(dst.join(name)), "{}", name);
+        }
+        for name in ["file-link", "dir-link/file.txt", "link-link", "hard-link"] {
+            assert_eq!(fs::read_to_string(dst.join(name)).unwrap(), "contents");
+        }
+        assert_ne!(ino(dst.join("hard-link")), ino(dst.join("dir/file.txt")));
+        assert!(dst.join("dir-link").is_dir());
+
+        for entry in &entries[1..] {
+            let result = unpack(&[entries[0], *entry], LinkPolicy::Reject);
+            assert!(
+                matches!(result, Err(Error::UnsafeArchiveEntry(_))),
+                "{}",
+                entry.0
+            );
+        }
+        assert!(unpack(&entries[..1], LinkPolicy::Reject).is_ok());
+
+        // Links out of the extraction directory are never allowed.
+        for links in [LinkPolicy::Keep, LinkPolicy::Dereference] {
+            let result = unpack(&[("escape", EntryType::Symlink, "../..")], links);
+            assert!(matches!(result, Err(Error::UnsafeArchiveEntry(_))));
+        }
+
+        // Links to a directory containing them can be kept, but can't be copied.
+        let looped = [
+            ("dir/file.txt", EntryType::Regular, "contents"),
+            ("dir/parent", EntryType::Symlink, ".."),
+        ];
+        assert!(unpack(&looped, LinkPolicy::Keep).is_ok());
+        let result = unpack(&looped, LinkPolicy::Dereference);
+        assert!(matches!(result, Err(Error::UnsafeArchiveEntry(_))));
+
+        let dangling = [("dangling", EntryType::Symlink, "missing.txt")];
+        assert!(unpack(&dangling, LinkPolicy::Keep).is_ok());
+        let result = unpack(&dangling, LinkPolicy::Dereference);
+        assert!(matches!(result, Err(Error::ExtractionError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_zip_link_policy() {
+        use zip::write::SimpleFileOptions;
+
+        let dir = tempdir().unwrap();
+        let archive = dir.path().join("archive.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        writer
+            .start_file("dir/file.txt", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(b"contents").unwrap();
+        writer
+            .add_symlink("dir-link", "dir", SimpleFileOptions::default())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let unpack = |links| {
+            let dst = tempdir().unwrap();
+            let options = UnpackOptions {
+                links,
+                ..Default::default()
+            };
+            unpack_archive(&archive, dst.path(), &ArchiveFormat::Zip, &options).map(|_| dst)
+        };
+        let dst = unpack(LinkPolicy::Keep).unwrap();
+        assert!(fs::symlink_metadata(dst.path().join("dir-link"))
+            .unwrap()
+            .is_symlink());
+        let dst = unpack(LinkPolicy::Dereference).unwrap();
+        let link = dst.path().join("dir-link");
+        assert!(fs::symlink_metadata(&link).unwrap().is_dir());
+        assert_eq!(
+            fs::read_to_string(link.join("file.txt")).unwrap(),
+            "contents"
+        );
+        let result = unpack(LinkPolicy::Reject);
+        assert!(matches!(result, Err(Error::UnsafeArchiveEntry(_))));
+    }
+
+    #[test]
+    fn test_includes() {
+        let options = UnpackOptions {
+            include: vec![
+                Pattern::new("*.json").unwrap(),
+                Pattern::new("data/**").unwrap(),
+            ],
+            exclude: vec![Pattern::new("data/*.tmp").unwrap()],
+            ..Default::default()
+        };
+        assert!(options.includes(Path::new("config.json")));
+        assert!(!options.includes(Path::new("nested/config.json")));
+        assert!(options.includes(Path::new("data/shard-0/part.bin")));
+        assert!(!options.includes(Path::new("data/part.tmp")));
+        assert!(!options.includes(Path::new("README.md")));
+        assert!(UnpackOptions::default().includes(Path::new("README.md")));
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(UnpackOptions::default().key(), None);
+        let key = |include: &[&str], exclude: &[&str]| {
+            UnpackOptions {
+                include: include.iter().map(|p| Pattern::new(p).unwrap()).collect(),
+                exclude: exclude.iter().map(|p| Pattern::new(p).unwrap()).collect(),
+                ..Default::default()
+            }
+            .key()
+            .unwrap()
+        };
+        assert_eq!(key(&["a", "b"], &[]), key(&["b", "a"], &[]));
+        assert_ne!(key(&["a"], &[]), key(&[], &["a"]));
+        assert_ne!(key(&["a"], &[]), key(&["a", "b"], &[]));
+
+        let strip = |strip_components, unwrap_single_dir| {
+            UnpackOptions {
+                strip_components,
+                unwrap_single_dir,
+                ..Default::default()
+            }
+            .key()
+        };
+        assert_eq!(strip(0, false), None);
+        assert!(strip(1, false).is_some());
+        assert_ne!(strip(1, false), strip(2, false));
+        assert_ne!(strip(1, false), strip(0, true));
+        assert_ne!(strip(1, false), strip(1, true));
+
+        let metadata = |metadata| {
+            UnpackOptions {
+                metadata,
+                ..Default::default()
+            }
+            .key()
+        };
+        assert_eq!(metadata(MetadataPolicy::Default), None);
+        assert_ne!(
+            metadata(MetadataPolicy::Preserve),
+            metadata(MetadataPolicy::ReadOnly)
+        );
+
+        let links = |links| {
+            UnpackOptions {
+                links,
+                ..Default::default()
+            }
+            .key()
+        };
+        assert_eq!(links(LinkPolicy::Keep), None);
+        assert_ne!(links(LinkPolicy::Dereference), links(LinkPolicy::Reject));
+    }
+
+    /// Build a tar archive out of existing files, given as `(name, path)` pairs.
+    fn build_tar_from(path: &Path, files: &[(&str, &Path)]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, file) in files {
+            builder.append_path_with_name(file, name).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_unpack_nested() {
+        let fixtures = Path::new("test_fixtures/utf-8_sample/archives");
+        let dir = tempdir().unwrap();
+        let inner = dir.path().join("inner.tar");
+        build_tar_from(&inner, &[("utf-8.tar.gz", &fixtures.join("utf-8.tar.gz"))]);
+        let outer = dir.path().join("outer.tar");
+        build_tar_from(
+            &outer,
+            &[
+                ("shards/a.tar.gz", &fixtures.join("utf-8.tar.gz")),
+                ("b.zip", &fixtures.join("utf-8.zip")),
+                ("inner.tar", &inner),
+                (
+                    "c.txt",
+                    Path::new("test_fixtures/utf-8_sample/utf-8_sample.txt"),
+                ),
+            ],
+        );
+        let unpack = |max_depth, limits| {
+            let dst = dir.path().join(format!("dst-{max_depth}"));
+            let _ = fs::remove_dir_all(&dst);
+            fs::create_dir(&dst).unwrap();
+            let options = UnpackOptions {
+                limits,
+                max_depth,
+                ..Default::default()
+            };
+            unpack_archive(&outer, &dst, &ArchiveFormat::Tar, &options).map(|_| dst)
+        };
+
+        let dst = unpack(0, ExtractionLimits::default()).unwrap();
+        assert!(dst.join("shards/a.tar.gz").is_file());
+        assert!(dst.join("b.zip").is_file());
+
+        let dst = unpack(1, ExtractionLimits::default()).unwrap();
+        assert!(dst.join("shards/a.tar.gz/dummy.txt").is_file());
+        assert!(dst.join("b.zip/folder/utf-8_sample.txt").is_file());
+        assert!(dst.join("inner.tar/utf-8.tar.gz").is_file());
+        assert!(dst.join("c.txt").is_file());
+        // The temp directories are all gone.
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), 4);
+
+        let dst = unpack(2, ExtractionLimits::default()).unwrap();
+        assert!(dst.join("inner.tar/utf-8.tar.gz/dummy.txt").is_file());
+
+        // The outer archive has 4 entries, so the limit is only exceeded by the nested ones.
+        let limits = ExtractionLimits {
+            max_entries: Some(6),
+            ..Default::default()
+        };
+        assert!(unpack(0, limits).is_ok());
+        assert!(matches!(
+            unpack(1, limits),
+            Err(Error::ExtractionLimitExceeded(_))
+        ));
+    }
+
+    fn archive_fixtures() -> Vec<&'static str> {
+        vec![
+            "utf-8.tar",
+            "utf-8.tar.gz",
+            #[cfg(any(feature = "lzma", feature = "xz-native"))]
+            "utf-8.tar.xz",
+            #[cfg(feature = "bzip2")]
+            "utf-8.tar.bz2",
+            #[cfg(feature = "zstd")]
+            "utf-8.tar.zst",
+            "utf-8.zip",
+            #[cfg(feature = "sevenz")]
+            "utf-8.7z",
+        ]
+    }
+
+    #[test]
+    fn test_progress() {
+        #[derive(Default)]
+        struct Counts {
+            bytes: usize,
+            entries: usize,
+            finished: bool,
+            abandoned: bool,
+        }
+        struct Counter(Arc<Mutex<Counts>>);
+        impl ExtractionProgress for Counter {
+            fn read(&mut self, bytes: usize) {
+                self.0.lock().unwrap().bytes += bytes;
+            }
+            fn unpacked(&mut self) {
+                self.0.lock().unwrap().entries += 1;
+            }
+            fn finish(&mut self) {
+                self.0.lock().unwrap().finished = true;
+            }
+            fn abandon(&mut self) {
+                self.0.lock().unwrap().abandoned = true;
+            }
+        }
+
+        let fixtures = Path::new("test_fixtures/utf-8_sample/archives");
+        for fixture in archive_fixtures() {
+            let path = fixtures.join(fixture);
+            let counts = Arc::new(Mutex::new(Counts::default()));
+            let options = UnpackOptions {
+                progress: Some(Progress::new(Counter(counts.clone()))),
+                ..Default::default()
+            };
+            let dir = tempdir().unwrap();
+            let format = ArchiveFormat::parse_from_extension(&path).unwrap();
+            unpack_archive(&path, dir.path(), &format, &options).unwrap();
+            options.progress.unwrap().finish();
+
+            let counts = counts.lock().unwrap();
+            let entries = ArchiveReader::open(&path).unwrap().entries().unwrap();
+            assert_eq!(counts.entries, entries.len(), "{}", fixture);
+            assert!(counts.bytes > 0, "{}", fixture);
+            if fixture.starts_with("utf-8.tar") {
+                // Tar archives are read once, up to the end-of-archive marker.
+                assert!(counts.bytes as u64 <= fs::metadata(&path).unwrap().len());
+            }
+            assert!(counts.finished);
+            assert!(!counts.abandoned);
+        }
+
+        // The progress is abandoned when an extraction fails.
+        let counts = Arc::new(Mutex::new(Counts::default()));
+        let options = UnpackOptions {
+            limits: ExtractionLimits {
+                max_entries: Some(1),
+                ..Default::default()
+            },
+            progress: Some(Progress::new(Counter(counts.clone()))),
+            ..Default::default()
+        };
+        let dir = tempdir().unwrap();
+        let path = fixtures.join("utf-8.tar.gz");
+        let result = unpack_archive(&path, dir.path(), &ArchiveFormat::TarGz, &options);
+        assert!(matches!(result, Err(Error::ExtractionLimitExceeded(_))));
+        assert!(!counts.lock().unwrap().abandoned);
+        drop(options);
+        let counts = counts.lock().unwrap();
+        assert!(counts.abandoned);
+        assert!(!counts.finished);
+    }
+
+    #[test]
+    fn test_extraction_manifest() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("extracted");
+        fs::create_dir_all(root.join("folder")).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("folder/b.txt"), "world").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+
+        let manifest_path = ExtractionManifest::path(&root);
+        assert_eq!(manifest_path, dir.path().join("extracted.manifest"));
+        ExtractionManifest::from_dir(&root, true)
+            .unwrap()
+            .write(&manifest_path)
+            .unwrap();
+        let with_digests = ExtractionManifest::read(&manifest_path).unwrap();
+        let without_digests = ExtractionManifest::from_dir(&root, false).unwrap();
+        with_digests.verify(&root).unwrap();
+        without_digests.verify(&root).unwrap();
+
+        // New files are fine.
+        fs::write(root.join("c.txt"), "new").unwrap();
+        with_digests.verify(&root).unwrap();
+
+        // Changing the contents is only caught with digests, unless the size changes too.
+        fs::write(root.join("folder/b.txt"), "earth").unwrap();
+        without_digests.verify(&root).unwrap();
+        assert!(matches!(
+            with_digests.verify(&root),
+            Err(Error::CacheCorrupted(_))
+        ));
+        fs::write(root.join("folder/b.txt"), "mars").unwrap();
+        assert!(matches!(
+            without_digests.verify(&root),
+            Err(Error::CacheCorrupted(_))
+        ));
+        fs::write(root.join("folder/b.txt"), "world").unwrap();
+
+        #[cfg(unix)]
+        {
+            fs::remove_file(root.join("link")).unwrap();
+            std::os::unix::fs::symlink("folder/b.txt", root.join("link")).unwrap();
+            assert!(matches!(
+                without_digests.verify(&root),
+                Err(Error::CacheCorrupted(_))
+            ));
+            fs::remove_file(root.join("link")).unwrap();
+            std::os::unix::fs::symlink("a.txt", root.join("link")).unwrap();
+        }
+
+        fs::remove_dir_all(root.join("folder")).unwrap();
+        assert!(matches!(
+            without_digests.verify(&root),
+            Err(Error::CacheCorrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_extracting_writer() {
+        let fixtures = Path::new("test_fixtures/utf-8_sample/archives");
+        let extract = |fixture: &str, options: &UnpackOptions| {
+            let dir = tempdir().unwrap();
+            let target = dir.path().join("target");
+            let data = fs::read(fixtures.join(fixture)).unwrap();
+            let mut written = Vec::new();
+            let mut writer = ExtractingWriter::new(&mut written);
+            writer.extract(&target, None, options).unwrap();
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            let result = writer.finish(&target);
+            // Everything is written either way.
+            assert_eq!(written, data, "{}", fixture);
+            result.map(|_| dir)
+        };
+
+        for fixture in archive_fixtures() {
+            if !fixture.starts_with("utf-8.tar") {
+                continue;
+            }
+            let dir = extract(fixture, &UnpackOptions::default()).unwrap();
+            let target = dir.path().join("target");
+            assert!(target.join("dummy.txt").is_file(), "{}", fixture);
+            assert!(target.join("folder/utf-8_sample.txt").is_file());
+            // The temp directory was moved into place.
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        }
+
+        assert!(matches!(
+            extract("utf-8.zip", &UnpackOptions::default()),
+            Err(Error::ExtractionError(_))
+        ));
+        let options = UnpackOptions {
+            limits: ExtractionLimits {
+                max_ratio: Some(1.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            extract("utf-8.tar.gz", &options),
+            Err(Error::ExtractionLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_archive_format() {
+        let fixtures = Path::new("test_fixtures/utf-8_sample/archives");
+        for fixture in archive_fixtures() {
+            let path = fixtures.join(fixture);
+            let format: ArchiveFormat = fixture.trim_start_matches("utf-8.").parse().unwrap();
+            assert_eq!(
+                format,
+                ArchiveFormat::parse_from_extension(&path).unwrap(),
+                "{}",
+                fixture
+            );
+            format.verify(&path).unwrap();
+        }
+        assert_eq!(
+            "tgz".parse::<ArchiveFormat>().unwrap(),
+            ArchiveFormat::TarGz
+        );
+
+        let tar_gz = fixtures.join("utf-8.tar.gz");
+        assert!(matches!(
+            ArchiveFormat::Zip.verify(&tar_gz),
+            Err(Error::ExtractionError(_))
+        ));
+        assert!(matches!(
+            ArchiveFormat::Tar.verify(&tar_gz),
+            Err(Error::ExtractionError(_))
+        ));
+        assert!(matches!(
+            ArchiveFormat::TarGz.verify(&fixtures.join("utf-8.zip")),
+            Err(Error::ExtractionError(_))
+        ));
+        assert!(matches!(
+            "rar".parse::<ArchiveFormat>(),
+            Err(Error::ConfigurationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_archive_reader() {
+        let expected = fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap();
+        for fixture in archive_fixtures() {
+            let mut archive =
+                ArchiveReader::open(Path::new("test_fixtures/utf-8_sample/archives").join(fixture))
+                    .unwrap();
+
+            let entries = archive.entries().unwrap();
+            let sample = entries
+                .iter()
+                .find(|entry| entry.path == Path::new("folder/utf-8_sample.txt"))
+                .unwrap_or_else(|| panic!("missing entry in {}", fixture));
+            assert_eq!(sample.size, expected.len() as u64);
+            assert!(!sample.is_dir);
+
+            // Members can be read in any order.
+            for _ in 0..2 {
+                let mut contents = Vec::new();
+                archive
+                    .open_member("./folder/utf-8_sample.txt")
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                assert_eq!(contents, expected, "{fixture}");
+                let mut dummy = Vec::new();
+                archive
+                    .open_member("dummy.txt")
+                    .unwrap()
+                    .read_to_end(&mut dummy)
+                    .unwrap();
+            }
+
+            assert!(matches!(
+                archive.open_member("missing.txt"),
+                Err(Error::ArchiveMemberNotFound(_))
+            ));
+            // Directories can't be read, and not all archives list them.
+            assert!(archive.open_member("folder").is_err());
+        }
+    }
+
+    #[cfg(feature = "sevenz")]
+    #[test]
+    fn test_7z_with_filters() {
+        let dir = tempdir().unwrap();
+        let options = UnpackOptions {
+            include: vec![Pattern::new("folder/*").unwrap()],
+            ..Default::default()
+        };
+        unpack_archive(
+            Path::new("test_fixtures/utf-8_sample/archives/utf-8.7z"),
+            dir.path(),
+            &ArchiveFormat::SevenZ,
+            &options,
+        )
+        .unwrap();
+        assert!(!dir.path().join("dummy.txt").exists());
+        assert!(dir.path().join("folder/utf-8_sample.txt").is_file());
     }
 }
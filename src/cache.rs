@@ -4,16 +4,29 @@ use log::{debug, error, info, warn};
 use rand::RngExt;
 use reqwest::blocking::{Client, ClientBuilder};
 use reqwest::header::ETAG;
+use sha2::{Digest, Sha256};
 use std::default::Default;
 use std::env;
 use std::fs::{self, OpenOptions};
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
 use std::thread;
 use std::time::{self, Duration};
 use tempfile::NamedTempFile;
 
-use crate::archives::{extract_archive, ArchiveFormat};
-use crate::utils::hash_str;
+use crate::archives::{
+    decompress_file, extract_archive, extract_layers, file_digest, ArchiveFormat,
+    CompressionFormat, ExtractingWriter, ExtractionLimits, ExtractionManifest, LinkPolicy,
+    MetadataPolicy, Progress, UnpackOptions,
+};
+#[cfg(feature = "ftp")]
+use crate::ftp::{self, FtpStream};
+#[cfg(feature = "git")]
+use crate::git::{self, GitResource};
+use crate::oci::{self, Descriptor, DigestWriter, Manifest, OciReference, RegistryClient};
+#[cfg(feature = "sftp")]
+use crate::sftp::{self, SftpClient};
+use crate::utils::{decode_data_uri, hash_str, local_path};
 #[cfg(feature = "progress-bar")]
 use crate::ProgressBar;
 use crate::{meta::Meta, Error};
@@ -34,6 +47,14 @@ struct Config {
     offline: bool,
     #[cfg(feature = "progress-bar")]
     progress_bar: Option<ProgressBar>,
+    #[cfg(any(feature = "ftp", feature = "sftp"))]
+    timeout: Option<Duration>,
+    #[cfg(feature = "sftp")]
+    sftp_private_key: Option<PathBuf>,
+    #[cfg(feature = "sftp")]
+    sftp_known_hosts: Option<PathBuf>,
+    extraction_limits: ExtractionLimits,
+    extraction_threads: usize,
 }
 
 impl CacheBuilder {
@@ -49,6 +70,14 @@ impl CacheBuilder {
                 offline: false,
                 #[cfg(feature = "progress-bar")]
                 progress_bar: Some(ProgressBar::default()),
+                #[cfg(any(feature = "ftp", feature = "sftp"))]
+                timeout: None,
+                #[cfg(feature = "sftp")]
+                sftp_private_key: None,
+                #[cfg(feature = "sftp")]
+                sftp_known_hosts: None,
+                extraction_limits: ExtractionLimits::default(),
+                extraction_threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
             },
         }
     }
@@ -75,6 +104,10 @@ impl CacheBuilder {
     /// Enable a request timeout.
     pub fn timeout(mut self, timeout: Duration) -> CacheBuilder {
         self.config.client_builder = self.config.client_builder.timeout(timeout);
+        #[cfg(any(feature = "ftp", feature = "sftp"))]
+        {
+            self.config.timeout = Some(timeout);
+        }
         self
     }
 
@@ -123,6 +156,53 @@ impl CacheBuilder {
         self
     }
 
+    /// Set the private key file to authenticate with for `sftp://` resources.
+    ///
+    /// If not set, the SSH agent and then the default keys in `~/.ssh/` are tried.
+    #[cfg(feature = "sftp")]
+    pub fn sftp_private_key(mut self, path: PathBuf) -> CacheBuilder {
+        self.config.sftp_private_key = Some(path);
+        self
+    }
+
+    /// Set the `known_hosts` file to check the host keys of `sftp://` servers against.
+    ///
+    /// If not set, `~/.ssh/known_hosts` is used.
+    #[cfg(feature = "sftp")]
+    pub fn sftp_known_hosts(mut self, path: PathBuf) -> CacheBuilder {
+        self.config.sftp_known_hosts = Some(path);
+        self
+    }
+
+    /// Set the maximum total size, in bytes, of the files extracted from an archive
+    /// or of a decompressed file. The default is no limit.
+    pub fn max_extracted_size(mut self, max_size: u64) -> CacheBuilder {
+        self.config.extraction_limits.max_size = Some(max_size);
+        self
+    }
+
+    /// Set the maximum number of entries an archive can have to be extracted.
+    /// The default is no limit.
+    pub fn max_archive_entries(mut self, max_entries: u64) -> CacheBuilder {
+        self.config.extraction_limits.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Set the maximum ratio between the total size of the files extracted from an
+    /// archive (or of a decompressed file) and the size of the archive itself.
+    /// The default is no limit.
+    pub fn max_compression_ratio(mut self, max_ratio: f64) -> CacheBuilder {
+        self.config.extraction_limits.max_ratio = Some(max_ratio);
+        self
+    }
+
+    /// Set the number of threads used to write the files of a zip archive.
+    /// The default is the number of available CPUs, and `1` extracts them one at a time.
+    pub fn extraction_threads(mut self, threads: usize) -> CacheBuilder {
+        self.config.extraction_threads = threads;
+        self
+    }
+
     /// Build the `Cache` object.
     pub fn build(self) -> Result<Cache, Error> {
         let dir = self.config.dir.unwrap_or_else(|| {
@@ -143,6 +223,14 @@ impl CacheBuilder {
             offline: self.config.offline,
             #[cfg(feature = "progress-bar")]
             progress_bar: self.config.progress_bar,
+            #[cfg(any(feature = "ftp", feature = "sftp"))]
+            timeout: self.config.timeout,
+            #[cfg(feature = "sftp")]
+            sftp_private_key: self.config.sftp_private_key,
+            #[cfg(feature = "sftp")]
+            sftp_known_hosts: self.config.sftp_known_hosts,
+            extraction_limits: self.config.extraction_limits,
+            extraction_threads: self.config.extraction_threads,
         })
     }
 }
@@ -154,7 +242,12 @@ impl Default for CacheBuilder {
 }
 
 /// Options to use with [`Cache::cached_path_with_options`].
-#[derive(Default)]
+///
+/// New options may be added in any release, so `Options` can't be built with a struct
+/// expression outside of this crate. Start from [`Options::default()`] or [`Options::new()`]
+/// and use the builder methods instead.
+#[derive(Default, Clone)]
+#[non_exhaustive]
 pub struct Options {
     /// An optional subdirectory (relative to the cache root) to cache the resource in.
     pub subdir: Option<String>,
@@ -162,6 +255,41 @@ pub struct Options {
     pub extract: bool,
     /// Force downloading the resource even if there's a cache hit.
     pub force: bool,
+    /// Expand `~` and environment variables (`$VAR` or `${VAR}`) in local resource paths.
+    pub expand_vars: bool,
+    /// Automatically decompress the resource, assuming the resource is a single
+    /// compressed file such as `foo.json.gz`.
+    pub decompress: bool,
+    /// When extracting, only extract entries whose path matches one of these glob patterns.
+    /// If empty, all entries are extracted.
+    pub include: Vec<String>,
+    /// When extracting, skip entries whose path matches any of these glob patterns.
+    pub exclude: Vec<String>,
+    /// When extracting, remove this many leading components from the path of each entry.
+    pub strip_components: usize,
+    /// When extracting, if the archive contains a single top-level directory,
+    /// return that directory instead.
+    pub unwrap_single_dir: bool,
+    /// When extracting, also extract archives found inside of the archive, up to this
+    /// many levels deep. If `0`, nested archives are left as-is.
+    pub recursive: usize,
+    /// The format of the archive to extract, like `"zip"` or `"tar.gz"`. If not set,
+    /// the format is detected from the contents of the resource.
+    pub archive_format: Option<String>,
+    /// Extract remote tar-based archives while they're being downloaded.
+    pub stream_extract: bool,
+    /// Check an existing extraction directory against its manifest, and extract the
+    /// archive again if anything is missing or was modified.
+    pub verify_extraction: bool,
+    /// Extract (or decompress) the resource again even if it's already been extracted.
+    pub force_extract: bool,
+    /// When extracting, how to set the permissions and timestamps of the extracted files.
+    pub metadata: MetadataPolicy,
+    /// When extracting, what to do with symlinks and hard links in the archive.
+    pub links: LinkPolicy,
+    /// When extracting a local archive, tell its versions apart by the digest of its
+    /// contents instead of by its modification time, size and inode.
+    pub local_content_hash: bool,
 }
 
 impl Options {
@@ -170,6 +298,20 @@ impl Options {
             subdir: subdir.map(String::from),
             extract,
             force,
+            expand_vars: false,
+            decompress: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            strip_components: 0,
+            unwrap_single_dir: false,
+            recursive: 0,
+            archive_format: None,
+            stream_extract: false,
+            verify_extraction: false,
+            force_extract: false,
+            metadata: MetadataPolicy::Default,
+            links: LinkPolicy::Keep,
+            local_content_hash: false,
         }
     }
 
@@ -190,6 +332,138 @@ impl Options {
         self.force = true;
         self
     }
+
+    /// Expand `~` and environment variables in local resource paths, e.g.
+    /// `~/models/x` or `$DATA_ROOT/x`.
+    pub fn expand_vars(mut self) -> Self {
+        self.expand_vars = true;
+        self
+    }
+
+    /// Treat the resource as a single compressed file (gzip, or xz, bzip2 and zstd
+    /// with the corresponding features) and decompress it.
+    pub fn decompress(mut self) -> Self {
+        self.decompress = true;
+        self
+    }
+
+    /// Only extract the archive entries matching the given glob pattern, like
+    /// `"configs/*.json"` or `"shards/00/**"`. Can be given multiple times.
+    ///
+    /// Patterns are matched against the full path of each entry, and `*` doesn't
+    /// match across directories.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.into());
+        self
+    }
+
+    /// Skip the archive entries matching the given glob pattern when extracting.
+    /// Can be given multiple times, and takes precedence over [`Options::include`].
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.into());
+        self
+    }
+
+    /// Remove the first `n` components from the path of each archive entry when
+    /// extracting, like `tar --strip-components`. Entries with `n` or fewer components
+    /// are skipped.
+    ///
+    /// Include and exclude patterns are still matched against the full paths.
+    pub fn strip_components(mut self, n: usize) -> Self {
+        self.strip_components = n;
+        self
+    }
+
+    /// When extracting an archive that contains everything in a single top-level
+    /// directory, like `project-1.2.3/`, return the path to the contents of that
+    /// directory instead of the directory that contains it.
+    pub fn unwrap_single_dir(mut self) -> Self {
+        self.unwrap_single_dir = true;
+        self
+    }
+
+    /// When extracting, also extract the archives found inside of the archive, like
+    /// a zip of `.tar.gz` shards, going up to `max_depth` levels deep.
+    ///
+    /// Each nested archive is replaced by a directory of the same name, e.g.
+    /// `shards/00.tar.gz/`, containing its entries. The extraction limits set with
+    /// [`CacheBuilder`] apply to everything extracted.
+    pub fn recursive(mut self, max_depth: usize) -> Self {
+        self.recursive = max_depth;
+        self
+    }
+
+    /// Extract the resource as an archive of the given format instead of detecting it,
+    /// for when detection gets it wrong, like with zip-based formats such as `.whl`.
+    ///
+    /// The supported formats are `"tar"`, `"tar.gz"` (or `"tgz"`), `"zip"`, and with the
+    /// corresponding features, `"tar.xz"`, `"tar.lzma"`, `"tar.bz2"`, `"tar.zst"` and `"7z"`.
+    /// Extraction fails with an [`Error::ExtractionError`] if the resource isn't
+    /// actually an archive of that format.
+    pub fn archive_format(mut self, format: &str) -> Self {
+        self.archive_format = Some(format.into());
+        self
+    }
+
+    /// When extracting a remote tar-based archive that needs to be downloaded, extract it
+    /// while it's being downloaded instead of reading it again afterwards.
+    ///
+    /// Other archives are extracted after the download as usual, and so is anything that
+    /// can't be extracted on the fly.
+    pub fn stream_extract(mut self) -> Self {
+        self.stream_extract = true;
+        self
+    }
+
+    /// Make sure the files in the extraction directory still match the archive, and
+    /// extract it again if not.
+    ///
+    /// A manifest of the extracted files is written next to each extraction directory.
+    /// With this option, the manifest also records a digest of each file, and the files
+    /// are compared against it. Otherwise only their sizes are compared.
+    pub fn verify_extraction(mut self) -> Self {
+        self.verify_extraction = true;
+        self
+    }
+
+    /// Extract (or decompress) the resource again, replacing the existing extraction
+    /// directory, without downloading the resource again like [`Options::force`] would.
+    pub fn force_extract(mut self) -> Self {
+        self.force_extract = true;
+        self
+    }
+
+    /// Set how the permissions, modification times and extended attributes of the
+    /// extracted files are set. See [`MetadataPolicy`] for the choices.
+    pub fn metadata(mut self, metadata: MetadataPolicy) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Set whether links in the archive are kept, replaced with copies of what they
+    /// point to, or rejected. See [`LinkPolicy`] for the choices.
+    pub fn links(mut self, links: LinkPolicy) -> Self {
+        self.links = links;
+        self
+    }
+
+    /// Tell the versions of a local archive apart by the digest of its contents, so
+    /// that it isn't extracted again when it's only touched or copied over with the
+    /// same contents. This means reading the whole archive on every call.
+    pub fn local_content_hash(mut self) -> Self {
+        self.local_content_hash = true;
+        self
+    }
+}
+
+/// How to extract a remote archive while it's being downloaded.
+struct StreamExtract<'a> {
+    /// The suffix of the extraction directory.
+    suffix: &'a str,
+    format: Option<ArchiveFormat>,
+    options: &'a UnpackOptions,
+    /// Whether to record the digests of the extracted files in the manifest.
+    digests: bool,
 }
 
 /// Fetches and manages resources in a local cache directory.
@@ -216,6 +490,19 @@ pub struct Cache {
     progress_bar: Option<ProgressBar>,
     /// The HTTP client used to fetch remote resources.
     http_client: Client,
+    /// The request timeout for FTP and SFTP connections.
+    #[cfg(any(feature = "ftp", feature = "sftp"))]
+    timeout: Option<Duration>,
+    /// The private key file used to authenticate SFTP connections.
+    #[cfg(feature = "sftp")]
+    sftp_private_key: Option<PathBuf>,
+    /// The `known_hosts` file that SFTP host keys are checked against.
+    #[cfg(feature = "sftp")]
+    sftp_known_hosts: Option<PathBuf>,
+    /// Limits that guard against archive bombs.
+    extraction_limits: ExtractionLimits,
+    /// The number of threads used to extract zip archives.
+    extraction_threads: usize,
 }
 
 impl Cache {
@@ -233,6 +520,11 @@ impl Cache {
     ///
     /// If the resource is local file, it's path is returned. If the resource is a static HTTP
     /// resource, it will cached locally and the path to the cache file will be returned.
+    ///
+    /// Local files can also be given as `file://` URLs, and inline data as `data:` URIs.
+    /// With the `ftp` and `sftp` features, `ftp://` and `sftp://` resources are cached just
+    /// like HTTP resources, using the size and modification time reported by the server in
+    /// place of an ETag.
     pub fn cached_path(&self, resource: &str) -> Result<PathBuf, Error> {
         self.cached_path_with_options(resource, &Options::default())
     }
@@ -268,57 +560,176 @@ impl Cache {
     /// ).unwrap();
     /// assert!(path.is_dir());
     /// ```
+    ///
+    /// Treat the resource as a compressed file and decompress it. The path returned is
+    /// the path to the decompressed file:
+    ///
+    /// ```rust,no_run
+    /// # use cached_path::{Cache, Options};
+    /// # let cache = Cache::new().unwrap();
+    /// let path = cache.cached_path_with_options(
+    ///     "https://example.com/data.json.gz",
+    ///     &Options::default().decompress(),
+    /// ).unwrap();
+    /// assert!(path.is_file());
+    /// ```
+    ///
+    /// Point to a file inside of an archive by separating the path of the file from
+    /// the archive with a `!`. The archive is extracted and the path returned is the
+    /// path to the file within the extraction directory. The archive must either be a local
+    /// file or a URL ending in an archive extension like `.tar.gz` or `.zip`, otherwise
+    /// the `!` is taken to be part of the resource:
+    ///
+    /// ```rust,no_run
+    /// # use cached_path::{Cache, Options};
+    /// # let cache = Cache::new().unwrap();
+    /// let path = cache.cached_path_with_options(
+    ///     "https://example.com/data.tar.gz!data/train.jsonl",
+    ///     &Options::default(),
+    /// ).unwrap();
+    /// assert!(path.is_file());
+    /// ```
     pub fn cached_path_with_options(
         &self,
         resource: &str,
         options: &Options,
     ) -> Result<PathBuf, Error> {
+        if let Some((archive, member)) = split_archive_member(resource, options.expand_vars) {
+            return self.cached_archive_member(archive, member, options);
+        }
+        if options.extract && options.decompress {
+            return Err(Error::ConfigurationError(
+                "Options::extract and Options::decompress are mutually exclusive".into(),
+            ));
+        }
+        let unpack_options = self.unpack_options(options)?;
+        let archive_format = options
+            .archive_format
+            .as_deref()
+            .map(str::parse::<ArchiveFormat>)
+            .transpose()?;
+        // Extracted archives and decompressed files are cached next to the resource. When
+        // only some entries are extracted, the directory name includes a key for the filters
+        // so that different subsets don't collide.
+        let suffix = if options.decompress {
+            String::from("-decompressed")
+        } else if let Some(key) = unpack_options.key() {
+            format!("-{key}-extracted")
+        } else {
+            String::from("-extracted")
+        };
+
         let cached_path: PathBuf;
         let mut extraction_dir: Option<PathBuf> = None;
+        // The layers of an OCI artifact to extract, in order.
+        let mut oci_layers: Option<Vec<PathBuf>> = None;
+
+        if resource.starts_with("git+") {
+            // This is a resource in a git repository, so check out the right version of the
+            // repository into the cache.
+            cached_path =
+                self.fetch_git_resource(resource, options.subdir.as_deref(), options.force)?;
 
-        if !resource.starts_with("http") {
+            if options.extract || options.decompress {
+                // Checkouts are immutable, so the path itself identifies the version.
+                extraction_dir = Some(self.resource_to_filepath(
+                    &cached_path.to_string_lossy(),
+                    &None,
+                    options.subdir.as_deref(),
+                    Some(&suffix),
+                ));
+            }
+        } else if resource.starts_with("data:") {
+            // This is inline data, so decode it into the cache.
+            cached_path = self.cache_data_uri(resource, options.subdir.as_deref())?;
+
+            if options.extract || options.decompress {
+                // The cache file is keyed by content, so the path identifies the version.
+                extraction_dir = Some(self.resource_to_filepath(
+                    &cached_path.to_string_lossy(),
+                    &None,
+                    options.subdir.as_deref(),
+                    Some(&suffix),
+                ));
+            }
+        } else if resource.starts_with("oci://") {
+            // This is an artifact in an OCI registry, so download its layers to the cache.
+            let (meta, layers) =
+                self.fetch_oci_artifact(resource, options.subdir.as_deref(), options.force)?;
+
+            if options.decompress {
+                return Err(Error::ConfigurationError(
+                    "OCI artifacts can't be decompressed, use Options::extract instead".into(),
+                ));
+            }
+            if archive_format.is_some() {
+                return Err(Error::ConfigurationError(
+                    "the format of OCI artifact layers is detected from their media types".into(),
+                ));
+            }
+            if options.extract {
+                extraction_dir = Some(meta.get_extraction_path(&suffix));
+                oci_layers = Some(layers);
+            }
+
+            cached_path = meta.resource_path;
+        } else if !is_remote(resource) {
             // If resource doesn't look like a URL, treat as local path, but return
             // an error if the path doesn't exist.
             info!("Treating {resource} as local file");
-            cached_path = PathBuf::from(resource);
+            cached_path = local_path(resource, options.expand_vars)?;
 
             if !cached_path.is_file() {
                 return Err(Error::ResourceNotFound(String::from(resource)));
             }
 
-            if options.extract {
+            if options.extract || options.decompress {
                 // If we need to extract, we extract into a unique subdirectory of the cache directory
                 // so as not to mess with the file system outside of the cache directory.
                 // To make sure that we use a unique directory for each "version" of this local
-                // resource, we treat the last modified time as an ETag.
-                let resource_last_modified = fs::metadata(resource)?
-                    .modified()
-                    .ok()
-                    .and_then(|sys_time| sys_time.elapsed().ok())
-                    .map(|duration| format!("{}", duration.as_secs()));
+                // resource, we treat its identity as an ETag.
+                let version = local_file_version(&cached_path, options.local_content_hash)?;
                 extraction_dir = Some(self.resource_to_filepath(
-                    resource,
-                    &resource_last_modified,
+                    &cached_path.to_string_lossy(),
+                    &Some(version),
                     options.subdir.as_deref(),
-                    Some("-extracted"),
+                    Some(&suffix),
                 ));
             }
         } else {
             // This is a remote resource, so fetch it to the cache.
-            let meta =
-                self.fetch_remote_resource(resource, options.subdir.as_deref(), options.force)?;
-
-            // Check if we need to extract.
-            if options.extract {
-                extraction_dir = Some(meta.get_extraction_path());
+            let stream = if options.extract && options.stream_extract {
+                Some(StreamExtract {
+                    suffix: &suffix,
+                    format: archive_format,
+                    options: &unpack_options,
+                    digests: options.verify_extraction,
+                })
+            } else {
+                None
+            };
+            let meta = self.fetch_remote_resource(
+                resource,
+                options.subdir.as_deref(),
+                options.force,
+                stream.as_ref(),
+            )?;
+
+            // Check if we need to extract or decompress.
+            if options.extract || options.decompress {
+                extraction_dir = Some(meta.get_extraction_path(&suffix));
             }
 
             cached_path = meta.resource_path;
         }
 
         if let Some(dirpath) = extraction_dir {
-            // Extract archive.
-            debug!("Treating {resource} as archive");
+            // Extract archive or decompress file.
+            if options.decompress {
+                debug!("Treating {resource} as compressed file");
+            } else {
+                debug!("Treating {resource} as archive");
+            }
 
             fs::create_dir_all(dirpath.parent().unwrap())?;
 
@@ -335,10 +746,61 @@ impl Cache {
             filelock.lock_exclusive()?;
             debug!("Lock on extraction directory acquired for {resource}");
 
-            if !dirpath.is_dir() {
+            let manifest_path = ExtractionManifest::path(&dirpath);
+            let mut extract = options.force_extract
+                || if options.decompress {
+                    !dirpath.is_file()
+                } else {
+                    !dirpath.is_dir()
+                };
+            if !extract && !options.decompress && options.verify_extraction {
+                let result = ExtractionManifest::read(&manifest_path)
+                    .and_then(|manifest| manifest.verify(&dirpath));
+                if let Err(err) = result {
+                    warn!("Extracting {resource} again since {dirpath:?} doesn't match its manifest: {err}");
+                    extract = true;
+                }
+            }
+
+            if !extract {
+                debug!("{resource} is already extracted to {dirpath:?}");
+            } else if options.decompress {
+                info!("Decompressing {resource} to {dirpath:?}");
+                let format = CompressionFormat::parse_from_extension(&cached_path)?;
+                decompress_file(&cached_path, &dirpath, &format, &self.extraction_limits)?;
+            } else {
+                if dirpath.exists() {
+                    // The manifest goes first so that it never describes a partial directory.
+                    if manifest_path.exists() {
+                        fs::remove_file(&manifest_path)?;
+                    }
+                    fs::remove_dir_all(&dirpath)?;
+                }
                 info!("Extracting {resource} to {dirpath:?}");
-                let format = ArchiveFormat::parse_from_extension(&cached_path)?;
-                extract_archive(&cached_path, &dirpath, &format)?;
+                let archives = match &oci_layers {
+                    Some(layers) => &layers[..],
+                    None => std::slice::from_ref(&cached_path),
+                };
+                let unpack_options = UnpackOptions {
+                    progress: self.extraction_progress(resource, archives)?,
+                    ..unpack_options.clone()
+                };
+                if let Some(layers) = &oci_layers {
+                    extract_layers(layers, &dirpath, &unpack_options)?;
+                } else {
+                    let format = match archive_format {
+                        Some(format) => {
+                            format.verify(&cached_path)?;
+                            format
+                        }
+                        None => ArchiveFormat::parse_from_extension(&cached_path)?,
+                    };
+                    extract_archive(&cached_path, &dirpath, &format, &unpack_options)?;
+                }
+                if let Some(progress) = &unpack_options.progress {
+                    progress.finish();
+                }
+                write_manifest(&dirpath, options.verify_extraction);
             }
 
             fs2::FileExt::unlock(&filelock)?;
@@ -351,6 +813,68 @@ impl Cache {
         }
     }
 
+    /// Get the path to a file inside of an archive, extracting the archive if needed.
+    fn cached_archive_member(
+        &self,
+        archive: &str,
+        member: &str,
+        options: &Options,
+    ) -> Result<PathBuf, Error> {
+        if options.decompress {
+            return Err(Error::ConfigurationError(
+                "a file inside of an archive can't be decompressed".into(),
+            ));
+        }
+        let member_path = Path::new(member);
+        if !member_path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::InvalidUrl(format!("{archive}!{member}")));
+        }
+
+        let options = Options {
+            extract: true,
+            ..options.clone()
+        };
+        let path = self
+            .cached_path_with_options(archive, &options)?
+            .join(member_path);
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(Error::ArchiveMemberNotFound(format!(
+                "'{member}' in {archive}"
+            )))
+        }
+    }
+
+    /// Collect the options that control how archives are unpacked.
+    fn unpack_options(&self, options: &Options) -> Result<UnpackOptions, Error> {
+        let parse = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern).map_err(|e| {
+                        Error::ConfigurationError(format!("invalid glob pattern '{pattern}': {e}"))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(UnpackOptions {
+            limits: self.extraction_limits,
+            include: parse(&options.include)?,
+            exclude: parse(&options.exclude)?,
+            strip_components: options.strip_components,
+            unwrap_single_dir: options.unwrap_single_dir,
+            max_depth: options.recursive,
+            progress: None,
+            threads: self.extraction_threads,
+            metadata: options.metadata,
+            links: options.links,
+        })
+    }
+
     /// A convenience method to get the cached path to a resource using the given
     /// cache subdirectory (relative to the cache root).
     ///
@@ -384,39 +908,25 @@ impl Cache {
         resource: &str,
         subdir: Option<&str>,
         force: bool,
+        stream: Option<&StreamExtract>,
     ) -> Result<Meta, Error> {
         // Otherwise we attempt to parse the URL.
         let url =
             reqwest::Url::parse(resource).map_err(|_| Error::InvalidUrl(String::from(resource)))?;
-
-        // Ensure root directory exists in case it has changed or been removed.
-        if let Some(subdir_path) = subdir {
-            fs::create_dir_all(self.dir.join(subdir_path))?;
-        } else {
-            fs::create_dir_all(&self.dir)?;
-        };
-
-        if !force {
-            // Find any existing cached versions of resource and check if they are still
-            // fresh according to the `freshness_lifetime` setting.
-            let versions = self.find_existing(resource, subdir); // already sorted, latest is first.
-            if self.offline {
-                if !versions.is_empty() {
-                    info!("Found existing cached version of {resource}");
-                    return Ok(versions[0].clone());
-                } else {
-                    error!("Offline mode is enabled but no cached versions of resource exist.");
-                    return Err(Error::NoCachedVersions(String::from(resource)));
-                }
-            } else if !versions.is_empty() && versions[0].is_fresh(self.freshness_lifetime) {
-                // Oh hey, the latest version is still fresh!
-                info!("Latest cached version of {resource} is still fresh");
-                return Ok(versions[0].clone());
+        match url.scheme() {
+            "http" | "https" => {}
+            "ftp" if cfg!(feature = "ftp") => {}
+            "sftp" if cfg!(feature = "sftp") => {}
+            scheme @ ("ftp" | "sftp") => {
+                return Err(Error::ConfigurationError(format!(
+                    "the '{scheme}' feature is required for {scheme}:// resources"
+                )));
             }
-        } else if self.offline {
-            return Err(Error::ConfigurationError(
-                "'force=true' is invalid with offline mode enabled".to_string(),
-            ));
+            _ => return Err(Error::InvalidUrl(String::from(resource))),
+        }
+
+        if let Some(meta) = self.find_fresh_version(resource, subdir, force, false)? {
+            return Ok(meta);
         }
 
         // No existing version or the existing versions are older than their freshness
@@ -453,7 +963,7 @@ impl Cache {
         }
 
         // No up-to-date version cached, so we have to try downloading it.
-        let meta = self.try_download_resource(resource, &url, &path, &etag)?;
+        let meta = self.try_download_resource(resource, &url, &path, &etag, stream)?;
 
         info!("New version of {resource} cached");
 
@@ -464,6 +974,287 @@ impl Cache {
         Ok(meta)
     }
 
+    /// Check out a resource from a git repository, returning the path to the checkout
+    /// or to the requested path within it.
+    #[cfg(feature = "git")]
+    fn fetch_git_resource(
+        &self,
+        resource: &str,
+        subdir: Option<&str>,
+        force: bool,
+    ) -> Result<PathBuf, Error> {
+        let git_resource = GitResource::parse(resource)?;
+        let meta = self.fetch_git_checkout(&git_resource, subdir, force)?;
+        match &git_resource.subpath {
+            Some(subpath) => {
+                let path = meta.resource_path.join(subpath);
+                if !path.exists() {
+                    return Err(Error::ResourceNotFound(format!(
+                        "{subpath} in {}",
+                        git_resource.key()
+                    )));
+                }
+                Ok(path)
+            }
+            None => Ok(meta.resource_path),
+        }
+    }
+
+    #[cfg(not(feature = "git"))]
+    fn fetch_git_resource(
+        &self,
+        _resource: &str,
+        _subdir: Option<&str>,
+        _force: bool,
+    ) -> Result<PathBuf, Error> {
+        Err(Error::ConfigurationError(
+            "the 'git' feature is required for git+ resources".into(),
+        ))
+    }
+
+    #[cfg(feature = "git")]
+    fn fetch_git_checkout(
+        &self,
+        resource: &GitResource,
+        subdir: Option<&str>,
+        force: bool,
+    ) -> Result<Meta, Error> {
+        let key = resource.key();
+
+        // Commits never change, so they never need to be revalidated.
+        if let Some(meta) = self.find_fresh_version(&key, subdir, force, resource.is_commit())? {
+            return Ok(meta);
+        }
+
+        // All resources from the same repository share a single bare mirror.
+        let mirror = self.resource_to_filepath(&resource.url, &None, subdir, Some(".git"));
+
+        debug!("Acquiring lock for git mirror of {}", resource.url);
+        let lock_path = format!("{}.lock", mirror.to_str().unwrap());
+        let filelock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(lock_path)?;
+        filelock.lock_exclusive()?;
+        debug!("Lock acquired for git mirror of {}", resource.url);
+
+        let commit = git::fetch(&mirror, resource)?;
+        let path = self.resource_to_filepath(&key, &Some(commit.clone()), subdir, None);
+
+        if path.exists() && force {
+            warn!("Forcing new checkout of {key} despite cache hit");
+            fs::remove_dir_all(&path)?;
+        }
+        if !path.exists() {
+            info!("Checking out {key} at {commit}");
+            git::checkout(&mirror, &commit, &path)?;
+        } else {
+            info!("Checkout of {key} is up-to-date");
+        }
+
+        // Always write a new meta so that the freshness lifetime starts over.
+        let meta = Meta::new(key, path, Some(commit), self.freshness_lifetime);
+        meta.to_file()?;
+
+        fs2::FileExt::unlock(&filelock)?;
+        debug!("Lock released for git mirror of {}", resource.url);
+
+        Ok(meta)
+    }
+
+    /// Download an artifact from an OCI registry into the cache, returning its meta and the
+    /// paths of its layers in order.
+    ///
+    /// Blobs are stored by digest in a `blobs/` directory of the cache, and each version of
+    /// the artifact gets a directory with links to its layers.
+    fn fetch_oci_artifact(
+        &self,
+        resource: &str,
+        subdir: Option<&str>,
+        force: bool,
+    ) -> Result<(Meta, Vec<PathBuf>), Error> {
+        let reference = OciReference::parse(resource)?;
+        let key = reference.key();
+
+        // Artifacts referenced by digest never change, so they never need to be revalidated.
+        if let Some(meta) = self.find_fresh_version(&key, subdir, force, reference.is_digest())? {
+            let manifest = Manifest::from_slice(&fs::read(
+                self.blob_path(meta.etag.as_deref().unwrap_or_default(), subdir)?,
+            )?)?;
+            let layers = oci_layer_paths(&meta.resource_path, &manifest)?;
+            return Ok((meta, layers));
+        }
+
+        let mut client = RegistryClient::new(&self.http_client, &reference);
+        let (digest, manifest_bytes) = client.fetch_manifest()?;
+        let manifest = Manifest::from_slice(&manifest_bytes)?;
+        let path = self.resource_to_filepath(&key, &Some(digest.clone()), subdir, None);
+
+        debug!("Acquiring lock for cache of {key}");
+        let lock_path = format!("{}.lock", path.to_str().unwrap());
+        let filelock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(lock_path)?;
+        filelock.lock_exclusive()?;
+        debug!("Lock acquired for {key}");
+
+        // The manifest is stored as a blob as well so that we know the layers of cached versions.
+        let manifest_path = self.blob_path(&digest, subdir)?;
+        if !manifest_path.is_file() {
+            let tempfile = NamedTempFile::new_in(manifest_path.parent().unwrap())?;
+            fs::write(tempfile.path(), &manifest_bytes)?;
+            fs::rename(tempfile.path(), &manifest_path)?;
+        }
+
+        if path.exists() && force {
+            warn!("Forcing re-download of {key} despite cache hit");
+            fs::remove_dir_all(&path)?;
+        }
+        if !path.exists() {
+            let temp_path = tempfile::tempdir_in(path.parent().unwrap())?;
+            for layer in &manifest.layers {
+                let blob_path = self.fetch_oci_blob(&mut client, resource, layer, subdir, force)?;
+                let layer_path = temp_path.path().join(layer.file_name()?);
+                fs::hard_link(&blob_path, &layer_path)
+                    .or_else(|_| fs::copy(&blob_path, &layer_path).map(|_| ()))?;
+            }
+            fs::rename(temp_path, &path)?;
+            info!("New version of {key} cached");
+        } else {
+            info!("Cached version of {key} is up-to-date");
+        }
+
+        // Always write a new meta so that the freshness lifetime starts over.
+        let meta = Meta::new(key, path, Some(digest), self.freshness_lifetime);
+        meta.to_file()?;
+
+        fs2::FileExt::unlock(&filelock)?;
+
+        let layers = oci_layer_paths(&meta.resource_path, &manifest)?;
+        Ok((meta, layers))
+    }
+
+    /// Download a blob from an OCI registry, verifying its digest.
+    fn fetch_oci_blob(
+        &self,
+        client: &mut RegistryClient,
+        resource: &str,
+        blob: &Descriptor,
+        subdir: Option<&str>,
+        force: bool,
+    ) -> Result<PathBuf, Error> {
+        let digest = &blob.digest;
+        let blob_path = self.blob_path(digest, subdir)?;
+        if blob_path.is_file() && !force {
+            debug!("Blob {digest} already cached");
+            return Ok(blob_path);
+        }
+
+        let mut response = client.fetch_blob(digest)?;
+        let tempfile = NamedTempFile::new_in(blob_path.parent().unwrap())?;
+        let mut writer = DigestWriter::new(OpenOptions::new().write(true).open(tempfile.path())?);
+        info!("Starting download of blob {digest}");
+        let content_length = response.content_length().or(blob.size);
+        let bytes =
+            self.copy_with_progress(resource, content_length, &mut response, &mut writer)?;
+        if blob.size.is_some_and(|size| size != bytes) {
+            return Err(Error::ProtocolError(format!(
+                "size mismatch for blob {digest}, expected {} bytes but got {bytes}",
+                blob.size.unwrap()
+            )));
+        }
+
+        let actual_digest = writer.digest();
+        if &actual_digest != digest {
+            return Err(Error::ProtocolError(format!(
+                "digest mismatch for blob, expected {digest} but got {actual_digest}"
+            )));
+        }
+        fs::rename(tempfile.path(), &blob_path)?;
+
+        Ok(blob_path)
+    }
+
+    /// The path to a content-addressed blob in the cache, creating the parent directory
+    /// if needed.
+    fn blob_path(&self, digest: &str, subdir: Option<&str>) -> Result<PathBuf, Error> {
+        let root = match subdir {
+            Some(subdir_path) => self.dir.join(subdir_path),
+            None => self.dir.clone(),
+        };
+        let blobs_dir = root.join("blobs").join("sha256");
+        fs::create_dir_all(&blobs_dir)?;
+        Ok(blobs_dir.join(oci::digest_hex(digest)?))
+    }
+
+    /// Decode a `data:` URI into a cache file keyed by the hash of its contents.
+    fn cache_data_uri(&self, resource: &str, subdir: Option<&str>) -> Result<PathBuf, Error> {
+        let data = decode_data_uri(resource)?;
+        let key = format!("data:sha256:{:x}", Sha256::digest(&data));
+        let path = self.resource_to_filepath(&key, &None, subdir, None);
+
+        if !path.is_file() {
+            fs::create_dir_all(path.parent().unwrap())?;
+            // Write to a temp file first so that other processes never see a partial file.
+            let tempfile = NamedTempFile::new_in(path.parent().unwrap())?;
+            fs::write(tempfile.path(), &data)?;
+            fs::rename(tempfile.path(), &path)?;
+            debug!("Decoded {} bytes of inline data to {path:?}", data.len());
+        }
+
+        Ok(path)
+    }
+
+    /// Check for an existing version of a resource that can be used without revalidating,
+    /// either because it's still fresh, or it's `immutable`, or we're in offline mode.
+    ///
+    /// This also makes sure the cache directory exists.
+    fn find_fresh_version(
+        &self,
+        key: &str,
+        subdir: Option<&str>,
+        force: bool,
+        immutable: bool,
+    ) -> Result<Option<Meta>, Error> {
+        // Ensure root directory exists in case it has changed or been removed.
+        if let Some(subdir_path) = subdir {
+            fs::create_dir_all(self.dir.join(subdir_path))?;
+        } else {
+            fs::create_dir_all(&self.dir)?;
+        };
+
+        if !force {
+            // Find any existing cached versions of the resource and check if they are still
+            // fresh according to the `freshness_lifetime` setting.
+            let versions = self.find_existing(key, subdir); // already sorted, latest is first.
+            if self.offline {
+                if !versions.is_empty() {
+                    info!("Found existing cached version of {key}");
+                    return Ok(Some(versions[0].clone()));
+                } else {
+                    error!("Offline mode is enabled but no cached versions of resource exist.");
+                    return Err(Error::NoCachedVersions(String::from(key)));
+                }
+            } else if !versions.is_empty()
+                && (immutable || versions[0].is_fresh(self.freshness_lifetime))
+            {
+                info!("Latest cached version of {key} is still fresh");
+                return Ok(Some(versions[0].clone()));
+            }
+        } else if self.offline {
+            return Err(Error::ConfigurationError(
+                "'force=true' is invalid with offline mode enabled".to_string(),
+            ));
+        }
+
+        Ok(None)
+    }
+
     /// Find existing versions of a cached resource, sorted by most recent first.
     fn find_existing(&self, resource: &str, subdir: Option<&str>) -> Vec<Meta> {
         let mut existing_meta: Vec<Meta> = vec![];
@@ -497,10 +1288,11 @@ impl Cache {
         url: &reqwest::Url,
         path: &Path,
         etag: &Option<String>,
+        stream: Option<&StreamExtract>,
     ) -> Result<Meta, Error> {
         let mut retries: u32 = 0;
         loop {
-            match self.download_resource(resource, url, path, etag) {
+            match self.download_resource(resource, url, path, etag, stream) {
                 Ok(meta) => {
                     return Ok(meta);
                 }
@@ -530,59 +1322,174 @@ impl Cache {
         url: &reqwest::Url,
         path: &Path,
         etag: &Option<String>,
+        stream: Option<&StreamExtract>,
     ) -> Result<Meta, Error> {
         debug!("Attempting connection to {url}");
 
-        let mut response = self
-            .http_client
-            .get(url.clone())
-            .send()?
-            .error_for_status()?;
-
-        debug!("Opened connection to {url}");
+        let meta = Meta::new(
+            String::from(resource),
+            path.into(),
+            etag.clone(),
+            self.freshness_lifetime,
+        );
 
         // First we make a temporary file and download the contents of the resource into it.
         // Otherwise if we wrote directly to the cache file and the download got
         // interrupted we could be left with a corrupted cache file.
         let tempfile = NamedTempFile::new_in(path.parent().unwrap())?;
-        let mut tempfile_write_handle = OpenOptions::new().write(true).open(tempfile.path())?;
+        let mut tempfile_write_handle =
+            ExtractingWriter::new(OpenOptions::new().write(true).open(tempfile.path())?);
+        if let Some(stream) = stream {
+            let extraction_path = meta.get_extraction_path(stream.suffix);
+            tempfile_write_handle.extract(&extraction_path, stream.format, stream.options)?;
+        }
+
+        let bytes = match url.scheme() {
+            #[cfg(feature = "ftp")]
+            "ftp" => {
+                let ftp_path = ftp::url_path(url)?;
+                let mut ftp = FtpStream::connect(url, self.timeout)?;
+                let content_length = ftp.size(&ftp_path)?;
+                debug!("Opened connection to {url}");
+                info!("Starting download of {url}");
+                let bytes = ftp.retrieve(&ftp_path, |reader| {
+                    self.copy_with_progress(
+                        resource,
+                        content_length,
+                        reader,
+                        &mut tempfile_write_handle,
+                    )
+                })?;
+                ftp.quit()?;
+                bytes
+            }
+            #[cfg(feature = "sftp")]
+            "sftp" => {
+                let sftp = SftpClient::connect(
+                    url,
+                    self.sftp_private_key.as_deref(),
+                    self.sftp_known_hosts.as_deref(),
+                    self.timeout,
+                )?;
+                let sftp_path = sftp::url_path(url);
+                let (content_length, _) = sftp.stat(&sftp_path)?;
+                let mut reader = sftp.open(&sftp_path)?;
+                debug!("Opened connection to {url}");
+                info!("Starting download of {url}");
+                self.copy_with_progress(
+                    resource,
+                    content_length,
+                    &mut reader,
+                    &mut tempfile_write_handle,
+                )?
+            }
+            _ => {
+                let mut response = self
+                    .http_client
+                    .get(url.clone())
+                    .send()?
+                    .error_for_status()?;
+                debug!("Opened connection to {url}");
+                info!("Starting download of {url}");
+                let content_length = response.content_length();
+                self.copy_with_progress(
+                    resource,
+                    content_length,
+                    &mut response,
+                    &mut tempfile_write_handle,
+                )?
+            }
+        };
 
-        info!("Starting download of {url}");
+        info!("Downloaded {bytes} bytes");
 
-        #[cfg(feature = "progress-bar")]
-        let bytes = if let Some(progress_bar) = &self.progress_bar {
-            let mut download_wrapper = progress_bar.wrap_download(
-                resource,
-                response.content_length(),
-                tempfile_write_handle,
+        // The archive and its extraction are committed together while holding the lock on
+        // the extraction directory, like any other extraction.
+        let extraction_lock = if let Some(stream) = stream {
+            let lock_path = format!(
+                "{}.lock",
+                meta.get_extraction_path(stream.suffix).to_str().unwrap()
             );
-            let bytes = response.copy_to(&mut download_wrapper)?;
-            download_wrapper.finish();
-            bytes
+            let filelock = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(lock_path)?;
+            filelock.lock_exclusive()?;
+            Some(filelock)
         } else {
-            response.copy_to(&mut tempfile_write_handle)?
+            None
         };
-        #[cfg(not(feature = "progress-bar"))]
-        let bytes = response.copy_to(&mut tempfile_write_handle)?;
 
-        info!("Downloaded {bytes} bytes");
         debug!("Writing meta file");
-
-        let meta = Meta::new(
-            String::from(resource),
-            path.into(),
-            etag.clone(),
-            self.freshness_lifetime,
-        );
         meta.to_file()?;
 
         debug!("Renaming temp file to cache location for {url}");
 
         fs::rename(tempfile.path(), path)?;
 
+        if let Some(stream) = stream {
+            let extraction_path = meta.get_extraction_path(stream.suffix);
+            let extracted = !extraction_path.is_dir();
+            match tempfile_write_handle.finish(&extraction_path) {
+                Ok(()) if extracted => {
+                    info!("Extracted {resource} to {extraction_path:?} while downloading");
+                    write_manifest(&extraction_path, stream.digests);
+                }
+                Ok(()) => {}
+                // The archive will be extracted from the cache file instead.
+                Err(err) => warn!("Failed to extract {resource} while downloading: {err}"),
+            }
+        }
+        if let Some(filelock) = extraction_lock {
+            fs2::FileExt::unlock(&filelock)?;
+        }
+
         Ok(meta)
     }
 
+    /// Copy the contents of a remote resource to `writer`, displaying a progress bar if
+    /// enabled.
+    #[cfg_attr(not(feature = "progress-bar"), allow(unused_variables))]
+    fn copy_with_progress<R: Read + ?Sized, W: Write>(
+        &self,
+        resource: &str,
+        content_length: Option<u64>,
+        reader: &mut R,
+        mut writer: W,
+    ) -> io::Result<u64> {
+        #[cfg(feature = "progress-bar")]
+        if let Some(progress_bar) = &self.progress_bar {
+            let mut download_wrapper = progress_bar.wrap_download(resource, content_length, writer);
+            let bytes = io::copy(reader, &mut download_wrapper)?;
+            download_wrapper.finish();
+            return Ok(bytes);
+        }
+        io::copy(reader, &mut writer)
+    }
+
+    /// Get a [`Progress`] to display the progress of extracting the given archives,
+    /// if enabled.
+    #[cfg_attr(not(feature = "progress-bar"), allow(unused_variables))]
+    fn extraction_progress(
+        &self,
+        resource: &str,
+        archives: &[PathBuf],
+    ) -> Result<Option<Progress>, Error> {
+        #[cfg(feature = "progress-bar")]
+        if let Some(progress_bar) = &self.progress_bar {
+            let mut archive_size = 0;
+            for archive in archives {
+                archive_size += fs::metadata(archive)?.len();
+            }
+            return Ok(Some(
+                progress_bar.extraction_progress(resource, archive_size),
+            ));
+        }
+        Ok(None)
+    }
+
     fn try_get_etag(&self, resource: &str, url: &reqwest::Url) -> Result<Option<String>, Error> {
         let mut retries: u32 = 0;
         loop {
@@ -609,6 +1516,13 @@ impl Cache {
     }
 
     fn get_etag(&self, url: &reqwest::Url) -> Result<Option<String>, Error> {
+        match url.scheme() {
+            #[cfg(feature = "ftp")]
+            "ftp" => return self.get_ftp_version(url),
+            #[cfg(feature = "sftp")]
+            "sftp" => return self.get_sftp_version(url),
+            _ => {}
+        }
         debug!("Fetching ETAG for {url}");
         let response = self
             .http_client
@@ -627,6 +1541,36 @@ impl Cache {
         }
     }
 
+    /// FTP has no notion of an ETag, so we use the size and modification time
+    /// of the file instead.
+    #[cfg(feature = "ftp")]
+    fn get_ftp_version(&self, url: &reqwest::Url) -> Result<Option<String>, Error> {
+        debug!("Fetching size and modification time for {url}");
+        let ftp_path = ftp::url_path(url)?;
+        let mut ftp = FtpStream::connect(url, self.timeout)?;
+        let size = ftp.size(&ftp_path)?;
+        let mtime = ftp.mdtm(&ftp_path)?;
+        ftp.quit()?;
+        Ok(version_from_size_and_mtime(size, mtime))
+    }
+
+    /// Like FTP, we use the size and modification time of the file in place of an ETag.
+    #[cfg(feature = "sftp")]
+    fn get_sftp_version(&self, url: &reqwest::Url) -> Result<Option<String>, Error> {
+        debug!("Fetching size and modification time for {url}");
+        let sftp = SftpClient::connect(
+            url,
+            self.sftp_private_key.as_deref(),
+            self.sftp_known_hosts.as_deref(),
+            self.timeout,
+        )?;
+        let (size, mtime) = sftp.stat(&sftp::url_path(url))?;
+        Ok(version_from_size_and_mtime(
+            size,
+            mtime.map(|t| t.to_string()),
+        ))
+    }
+
     fn resource_to_filepath(
         &self,
         resource: &str,
@@ -656,6 +1600,104 @@ impl Cache {
     }
 }
 
+/// File extensions that mark a remote resource as an archive that members can be taken from.
+const ARCHIVE_EXTENSIONS: &[&str] = &[
+    ".tar",
+    ".tar.gz",
+    ".tgz",
+    ".tar.xz",
+    ".txz",
+    ".tar.lzma",
+    ".tar.bz2",
+    ".tbz2",
+    ".tar.zst",
+    ".tzst",
+    ".zip",
+    ".7z",
+];
+
+/// Split a resource like `https://host/x.tar.gz!data/train.jsonl` into the archive
+/// and the path of a file inside of it.
+///
+/// Since '!' can also be part of a URL or file name, the resource is only split when the
+/// archive is an existing local file, or a remote URL with a known archive extension.
+fn split_archive_member(resource: &str, expand_vars: bool) -> Option<(&str, &str)> {
+    if resource.starts_with("data:") || Path::new(resource).exists() {
+        return None;
+    }
+    let (archive, member) = resource
+        .rsplit_once('!')
+        .filter(|(archive, member)| !archive.is_empty() && !member.is_empty())?;
+    let is_archive = if is_remote(archive) {
+        reqwest::Url::parse(archive).is_ok_and(|url| {
+            let path = url.path().to_lowercase();
+            ARCHIVE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+        })
+    } else {
+        local_path(archive, expand_vars).is_ok_and(|path| path.is_file())
+    };
+    is_archive.then_some((archive, member))
+}
+
+/// Write the manifest of a new extraction directory. Without a manifest the directory
+/// just can't be verified, so failing to write it isn't an error.
+fn write_manifest(dir: &Path, digests: bool) {
+    let result = ExtractionManifest::from_dir(dir, digests)
+        .and_then(|manifest| manifest.write(&ExtractionManifest::path(dir)));
+    if let Err(err) = result {
+        warn!("Failed to write the manifest of {dir:?}: {err}");
+    }
+}
+
+/// Identify a version of a local file, either by its modification time, size and inode,
+/// which change whenever the file is written or replaced, or by a digest of its contents.
+fn local_file_version(path: &Path, content_hash: bool) -> Result<String, Error> {
+    if content_hash {
+        return file_digest(path);
+    }
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_nanos());
+    #[allow(unused_mut)]
+    let mut version = format!("mtime={mtime},size={}", metadata.len());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        version.push_str(&format!(",dev={},ino={}", metadata.dev(), metadata.ino()));
+    }
+    Ok(version)
+}
+
+/// Check if a resource looks like a URL that needs to be fetched.
+fn is_remote(resource: &str) -> bool {
+    resource.starts_with("http")
+        || resource.starts_with("ftp://")
+        || resource.starts_with("sftp://")
+}
+
+fn oci_layer_paths(artifact_dir: &Path, manifest: &Manifest) -> Result<Vec<PathBuf>, Error> {
+    manifest
+        .layers
+        .iter()
+        .map(|layer| Ok(artifact_dir.join(layer.file_name()?)))
+        .collect()
+}
+
+#[cfg(any(feature = "ftp", feature = "sftp"))]
+fn version_from_size_and_mtime(size: Option<u64>, mtime: Option<String>) -> Option<String> {
+    match (size, mtime) {
+        (None, None) => None,
+        (size, mtime) => Some(format!(
+            "{}-{}",
+            size.map(|s| s.to_string()).unwrap_or_default(),
+            mtime.unwrap_or_default()
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -759,4 +1801,31 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_split_archive_member() {
+        let archive = "test_fixtures/utf-8_sample/archives/utf-8.tar.gz";
+        assert_eq!(
+            split_archive_member(&format!("{archive}!dummy.txt"), false),
+            Some((archive, "dummy.txt"))
+        );
+        assert_eq!(
+            split_archive_member("https://host/data.TAR.GZ!data/train.jsonl", false),
+            Some(("https://host/data.TAR.GZ", "data/train.jsonl"))
+        );
+        assert_eq!(
+            split_archive_member("https://host/x.zip?sig=abc!a.txt", false),
+            Some(("https://host/x.zip?sig=abc", "a.txt"))
+        );
+
+        assert_eq!(split_archive_member("https://host/a!b.txt", false), None);
+        assert_eq!(
+            split_archive_member("https://host/x.txt?q=a!b", false),
+            None
+        );
+        assert_eq!(split_archive_member("file:///missing!b", false), None);
+        assert_eq!(split_archive_member("data:,a!b", false), None);
+        assert_eq!(split_archive_member("data:,x.zip!b", false), None);
+        assert_eq!(split_archive_member("missing.tar.gz!b", false), None);
+    }
 }
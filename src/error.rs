@@ -28,6 +28,23 @@ pub enum Error {
     #[error("Extracting archive failed ({0})")]
     ExtractionError(String),
 
+    /// Arises when an archive contains an entry that would be extracted outside of the
+    /// extraction directory, like an absolute path, a path with `..`, or a link that
+    /// points outside of the directory. This is also used for links when they're
+    /// rejected with [`LinkPolicy::Reject`](crate::LinkPolicy::Reject).
+    #[error("Unsafe archive entry ({0})")]
+    UnsafeArchiveEntry(String),
+
+    /// Arises when extracting or decompressing a resource would exceed one of the limits
+    /// set through the [`CacheBuilder`](crate::CacheBuilder).
+    #[error("Extraction limit exceeded ({0})")]
+    ExtractionLimitExceeded(String),
+
+    /// Arises when a resource points to a file inside of an archive, like
+    /// `https://host/x.tar.gz!data/train.jsonl`, but the archive doesn't contain that file.
+    #[error("File not found in archive ({0})")]
+    ArchiveMemberNotFound(String),
+
     /// Any IO error that could arise while attempting to cache a remote resource.
     #[error("An IO error occurred")]
     IoError(#[from] std::io::Error),
@@ -36,6 +53,11 @@ pub enum Error {
     #[error(transparent)]
     HttpError(#[from] reqwest::Error),
 
+    /// An error that could occur while fetching a remote resource over a protocol
+    /// other than HTTP, such as FTP or SFTP.
+    #[error("Protocol error ({0})")]
+    ProtocolError(String),
+
     /// Raise when configuration options are invalid.
     #[error("Configuration error ({0})")]
     ConfigurationError(String),
@@ -0,0 +1,218 @@
+//! Support for resources that live in git repositories, such as
+//! `git+https://github.com/org/repo.git@v1.0#configs/model.json`.
+
+use git2::build::CheckoutBuilder;
+use git2::{Cred, CredentialType, FetchOptions, Oid, RemoteCallbacks, Repository};
+use log::debug;
+use std::fs;
+use std::path::{Component, Path};
+use tempfile::tempdir_in;
+
+use crate::Error;
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Self {
+        Error::ProtocolError(format!("git error: {}", err.message()))
+    }
+}
+
+/// A parsed `git+<url>[@<ref>][#<path>]` resource.
+#[derive(Debug, PartialEq)]
+pub(crate) struct GitResource {
+    /// The URL of the repository, without the `git+` prefix.
+    pub(crate) url: String,
+    /// The branch, tag, or commit to check out. If `None`, the remote's `HEAD` is used.
+    pub(crate) reference: Option<String>,
+    /// An optional path within the repository.
+    pub(crate) subpath: Option<String>,
+}
+
+impl GitResource {
+    pub(crate) fn parse(resource: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidUrl(String::from(resource));
+        let rest = resource.strip_prefix("git+").ok_or_else(invalid)?;
+        let (rest, subpath) = match rest.split_once('#') {
+            Some((rest, subpath)) => (rest, Some(subpath.trim_matches('/'))),
+            None => (rest, None),
+        };
+        // The ref is separated from the URL by the last '@' in the path, so as not to
+        // be confused with the user part of the URL, e.g. 'ssh://git@host/repo.git@main'.
+        let path_start = rest
+            .find("://")
+            .and_then(|i| rest[i + 3..].find('/').map(|j| i + 3 + j))
+            .ok_or_else(invalid)?;
+        let (url, reference) = match rest[path_start..].rfind('@') {
+            Some(i) => (&rest[..path_start + i], Some(&rest[path_start + i + 1..])),
+            None => (rest, None),
+        };
+        if reference == Some("") {
+            return Err(invalid());
+        }
+        // The subpath must stay inside of the checkout.
+        if let Some(subpath) = subpath {
+            if !Path::new(subpath)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(invalid());
+            }
+        }
+        Ok(GitResource {
+            url: url.to_string(),
+            reference: reference.map(String::from),
+            subpath: subpath.filter(|s| !s.is_empty()).map(String::from),
+        })
+    }
+
+    /// A string identifying the repository and ref, regardless of the subpath.
+    pub(crate) fn key(&self) -> String {
+        format!(
+            "git+{}@{}",
+            self.url,
+            self.reference.as_deref().unwrap_or("HEAD")
+        )
+    }
+
+    /// Check if the ref is a full commit hash, which means it can never point to
+    /// a different version of the repository.
+    pub(crate) fn is_commit(&self) -> bool {
+        self.reference
+            .as_deref()
+            .is_some_and(|r| r.len() == 40 && r.chars().all(|c| c.is_ascii_hexdigit()))
+    }
+}
+
+/// Fetch the latest refs of the repository into a bare mirror, creating it if needed,
+/// and resolve the ref of the resource to a commit hash.
+pub(crate) fn fetch(mirror: &Path, resource: &GitResource) -> Result<String, Error> {
+    let repo = if mirror.is_dir() {
+        Repository::open_bare(mirror)?
+    } else {
+        debug!("Creating git mirror of {} at {mirror:?}", resource.url);
+        Repository::init_bare(mirror)?
+    };
+
+    // A commit we already have can't have changed, so there's no need to fetch.
+    if resource.is_commit() {
+        if let Ok(commit) = resolve(&repo, resource.reference.as_deref()) {
+            return Ok(commit);
+        }
+    }
+
+    debug!("Fetching {}", resource.url);
+    let mut remote = repo.remote_anonymous(&resource.url)?;
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks());
+    remote.fetch(
+        &[
+            "+refs/heads/*:refs/heads/*",
+            "+refs/tags/*:refs/tags/*",
+            "+HEAD:refs/remotes/origin/HEAD",
+        ],
+        Some(&mut options),
+        None,
+    )?;
+
+    resolve(&repo, resource.reference.as_deref())
+}
+
+/// Resolve a ref to a commit hash without fetching.
+fn resolve(repo: &Repository, reference: Option<&str>) -> Result<String, Error> {
+    let spec = reference.unwrap_or("refs/remotes/origin/HEAD");
+    let commit = repo
+        .revparse_single(spec)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|_| Error::ProtocolError(format!("unable to resolve git ref '{spec}'")))?;
+    Ok(commit.id().to_string())
+}
+
+/// Check out a commit from the bare mirror into `target`.
+///
+/// Like archive extraction, we first check out into a temporary directory and then
+/// rename it so that a partial checkout is never visible.
+pub(crate) fn checkout(mirror: &Path, commit: &str, target: &Path) -> Result<(), Error> {
+    let repo = Repository::open_bare(mirror)?;
+    let commit = repo.find_commit(Oid::from_str(commit)?)?;
+    let temp_target = tempdir_in(target.parent().unwrap())?;
+
+    debug!("Checking out {} into {target:?}", commit.id());
+    let mut builder = CheckoutBuilder::new();
+    builder.target_dir(temp_target.path()).force();
+    repo.checkout_tree(commit.as_object(), Some(&mut builder))?;
+
+    fs::rename(temp_target, target)?;
+
+    Ok(())
+}
+
+/// Callbacks to authenticate with the SSH agent or git's configured credential helpers.
+fn callbacks() -> RemoteCallbacks<'static> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username, allowed| {
+        // libgit2 keeps asking for credentials as long as authentication fails,
+        // so we need to give up at some point.
+        attempts += 1;
+        if attempts > 3 {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        if allowed.contains(CredentialType::SSH_KEY) {
+            Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            let config = git2::Config::open_default()?;
+            Cred::credential_helper(&config, url, username)
+        } else {
+            Cred::default()
+        }
+    });
+    callbacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            GitResource::parse("git+https://host/repo.git@v1.0#configs/x.json").unwrap(),
+            GitResource {
+                url: "https://host/repo.git".into(),
+                reference: Some("v1.0".into()),
+                subpath: Some("configs/x.json".into()),
+            }
+        );
+        assert_eq!(
+            GitResource::parse("git+ssh://git@host/org/repo.git@feature/x").unwrap(),
+            GitResource {
+                url: "ssh://git@host/org/repo.git".into(),
+                reference: Some("feature/x".into()),
+                subpath: None,
+            }
+        );
+        assert_eq!(
+            GitResource::parse("git+file:///tmp/repo").unwrap(),
+            GitResource {
+                url: "file:///tmp/repo".into(),
+                reference: None,
+                subpath: None,
+            }
+        );
+        assert!(GitResource::parse("git+https://host/repo.git@").is_err());
+        assert!(GitResource::parse("git+https://host/repo.git@main#../../..").is_err());
+        assert!(GitResource::parse("git+https://host/repo.git@main#configs/../..").is_err());
+        assert!(GitResource::parse("git+https://host/repo.git#./x.json").is_err());
+        assert!(GitResource::parse("git+repo").is_err());
+    }
+
+    #[test]
+    fn test_is_commit() {
+        let resource = GitResource::parse(
+            "git+https://host/repo.git@0123456789abcdef0123456789abcdef01234567",
+        )
+        .unwrap();
+        assert!(resource.is_commit());
+        let resource = GitResource::parse("git+https://host/repo.git@main").unwrap();
+        assert!(!resource.is_commit());
+    }
+}
@@ -68,6 +68,18 @@
 //! README.md
 //! ```
 //!
+//! You can also point directly to a file inside of an archive by separating it from the
+//! archive with a `!`. The archive is extracted and the path to the file is returned:
+//!
+//! ```rust
+//! use cached_path::cached_path;
+//!
+//! let path = cached_path(
+//!     "https://raw.githubusercontent.com/epwalsh/rust-cached-path/main/test_fixtures/utf-8_sample/archives/utf-8.tar.gz!dummy.txt",
+//! ).unwrap();
+//! assert!(path.is_file());
+//! ```
+//!
 //! It's also easy to customize the cache location, the HTTP client, and other options
 //! using a [`CacheBuilder`](crate::cache::CacheBuilder) to construct a custom
 //! [`Cache`](crate::cache::Cache) object. This is the recommended thing
@@ -95,11 +107,19 @@ use std::path::PathBuf;
 pub(crate) mod archives;
 mod cache;
 mod error;
+#[cfg(feature = "ftp")]
+mod ftp;
+#[cfg(feature = "git")]
+mod git;
 pub(crate) mod meta;
+mod oci;
 #[cfg(feature = "progress-bar")]
 mod progress_bar;
+#[cfg(feature = "sftp")]
+mod sftp;
 pub(crate) mod utils;
 
+pub use crate::archives::{ArchiveEntry, ArchiveReader, LinkPolicy, MetadataPolicy};
 pub use crate::cache::{Cache, CacheBuilder, Options};
 pub use crate::error::Error;
 #[cfg(feature = "progress-bar")]
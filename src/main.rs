@@ -1,4 +1,4 @@
-use cached_path::{Cache, Error, Options, ProgressBar};
+use cached_path::{Cache, Error, LinkPolicy, MetadataPolicy, Options, ProgressBar};
 use color_eyre::eyre::Result;
 use log::debug;
 use std::path::PathBuf;
@@ -29,6 +29,65 @@ struct Opt {
     /// Extract the resource as an archive.
     extract: bool,
 
+    #[structopt(long = "decompress", conflicts_with = "extract")]
+    /// Decompress the resource as a single compressed file, like a '.gz' file.
+    decompress: bool,
+
+    #[structopt(long = "include")]
+    /// Only extract archive entries matching this glob pattern. Can be given multiple times.
+    include: Vec<String>,
+
+    #[structopt(long = "exclude")]
+    /// Skip archive entries matching this glob pattern. Can be given multiple times.
+    exclude: Vec<String>,
+
+    #[structopt(long = "strip-components", default_value = "0")]
+    /// Remove this many leading path components from archive entries when extracting.
+    strip_components: usize,
+
+    #[structopt(long = "unwrap-single-dir")]
+    /// If the archive contains a single top-level directory, return that directory.
+    unwrap_single_dir: bool,
+
+    #[structopt(long = "recursive", default_value = "0")]
+    /// Also extract archives inside of the archive, up to this many levels deep.
+    recursive: usize,
+
+    #[structopt(long = "archive-format")]
+    /// The format of the archive to extract, like 'zip' or 'tar.gz', instead of detecting it.
+    archive_format: Option<String>,
+
+    #[structopt(long = "stream-extract")]
+    /// Extract a remote tar-based archive while it's being downloaded.
+    stream_extract: bool,
+
+    #[structopt(long = "verify-extraction")]
+    /// Check the extracted files and extract the archive again if any are missing or modified.
+    verify_extraction: bool,
+
+    #[structopt(long = "force-extract")]
+    /// Extract the archive again even if it's already been extracted.
+    force_extract: bool,
+
+    #[structopt(long = "metadata", default_value = "default")]
+    /// How to set the permissions and timestamps of extracted files: "default", "preserve"
+    /// (including setuid bits and extended attributes) or "read-only".
+    metadata: MetadataPolicy,
+
+    #[structopt(long = "links", default_value = "keep")]
+    /// What to do with links in archives: "keep" them, "dereference" them into copies, or
+    /// "reject" archives that have any.
+    links: LinkPolicy,
+
+    #[structopt(long = "local-content-hash")]
+    /// Tell versions of a local archive apart by the digest of its contents instead of its
+    /// modification time, size and inode.
+    local_content_hash: bool,
+
+    #[structopt(long = "extraction-threads")]
+    /// Set the number of threads used to extract zip archives. Defaults to the number of CPUs.
+    extraction_threads: Option<usize>,
+
     #[structopt(long = "timeout")]
     /// Set a request timeout.
     timeout: Option<u64>,
@@ -59,7 +118,7 @@ struct Opt {
     force: bool,
 
     #[structopt(short = "-q", long = "quietly")]
-    /// Disable the progress bar for downloads.
+    /// Disable the progress bars for downloads and extraction.
     quietly: bool,
 }
 
@@ -71,7 +130,20 @@ fn main() -> Result<()> {
     debug!("{opt:?}");
 
     let cache = build_cache_from_opt(&opt)?;
-    let options = Options::new(opt.subdir.as_deref(), opt.extract, opt.force);
+    let mut options = Options::new(opt.subdir.as_deref(), opt.extract, opt.force);
+    options.decompress = opt.decompress;
+    options.include = opt.include.clone();
+    options.exclude = opt.exclude.clone();
+    options.strip_components = opt.strip_components;
+    options.unwrap_single_dir = opt.unwrap_single_dir;
+    options.recursive = opt.recursive;
+    options.archive_format = opt.archive_format.clone();
+    options.stream_extract = opt.stream_extract;
+    options.verify_extraction = opt.verify_extraction;
+    options.force_extract = opt.force_extract;
+    options.metadata = opt.metadata;
+    options.links = opt.links;
+    options.local_content_hash = opt.local_content_hash;
     let path = cache.cached_path_with_options(&opt.resource, &options)?;
     println!("{}", path.to_string_lossy());
 
@@ -92,6 +164,9 @@ fn build_cache_from_opt(opt: &Opt) -> Result<Cache, Error> {
     if let Some(freshness_lifetime) = opt.freshness_lifetime {
         cache_builder = cache_builder.freshness_lifetime(freshness_lifetime);
     }
+    if let Some(extraction_threads) = opt.extraction_threads {
+        cache_builder = cache_builder.extraction_threads(extraction_threads);
+    }
     cache_builder = cache_builder.max_retries(opt.max_retries);
     cache_builder = cache_builder.max_backoff(opt.max_backoff);
     if !opt.quietly {
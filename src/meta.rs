@@ -53,9 +53,11 @@ impl Meta {
         meta_path
     }
 
-    pub(crate) fn get_extraction_path(&self) -> PathBuf {
+    /// Get the path to extract or decompress the resource to, which sits next to
+    /// the resource and ends with `suffix`.
+    pub(crate) fn get_extraction_path(&self, suffix: &str) -> PathBuf {
         let dirname = format!(
-            "{}-extracted",
+            "{}{suffix}",
             self.resource_path.file_name().unwrap().to_str().unwrap()
         );
         self.resource_path.parent().unwrap().join(dirname)
@@ -1,6 +1,8 @@
 use std::io::{self, Write};
 use std::time::Instant;
 
+use crate::archives::{ExtractionProgress, Progress};
+
 /// Progress bar types.
 ///
 /// This can be set with
@@ -30,6 +32,13 @@ impl ProgressBar {
         };
         DownloadWrapper::new(bar, writer)
     }
+
+    pub(crate) fn extraction_progress(&self, resource: &str, archive_size: u64) -> Progress {
+        match self {
+            ProgressBar::Full => Progress::new(FullExtractionBar::new(archive_size)),
+            ProgressBar::Light => Progress::new(LightExtractionBar::new(resource, archive_size)),
+        }
+    }
 }
 
 pub(crate) struct DownloadWrapper<W: Write> {
@@ -195,3 +204,104 @@ impl DownloadBar for LightDownloadBar {
         io::stderr().flush().ok();
     }
 }
+
+pub(crate) struct FullExtractionBar {
+    bar: indicatif::ProgressBar,
+    entries: u64,
+}
+
+impl FullExtractionBar {
+    pub(crate) fn new(archive_size: u64) -> Self {
+        let bar = indicatif::ProgressBar::new(archive_size);
+        bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .progress_chars("=>-")
+                .template(
+                    "{msg:.bold.cyan/blue} [{bar:20.cyan/blue}][{percent}%] {bytes}/{total_bytes:.bold} |{bytes_per_sec}| {prefix} entries",
+                )
+                .expect("Failed to set progress bar template"),
+        );
+        bar.set_message("Extracting");
+        bar.set_prefix("0");
+        bar.enable_steady_tick(std::time::Duration::from_millis(100));
+        Self { bar, entries: 0 }
+    }
+}
+
+impl ExtractionProgress for FullExtractionBar {
+    fn read(&mut self, bytes: usize) {
+        self.bar.inc(bytes as u64);
+    }
+
+    fn unpacked(&mut self) {
+        self.entries += 1;
+        self.bar.set_prefix(self.entries.to_string());
+    }
+
+    fn finish(&mut self) {
+        self.bar.set_message("Extracted");
+        self.bar.set_style(
+            indicatif::ProgressStyle::default_bar()
+                .template("{msg:.green.bold} {prefix:.bold} entries from {total_bytes:.bold} in {elapsed}")
+                .expect("Failed to set finish progress bar template"),
+        );
+        self.bar.finish();
+    }
+
+    fn abandon(&mut self) {
+        self.bar.abandon_with_message("Extraction failed");
+    }
+}
+
+pub(crate) struct LightExtractionBar {
+    start_time: Instant,
+    entries: u64,
+    bytes_since_last_update: usize,
+}
+
+impl LightExtractionBar {
+    pub(crate) fn new(resource: &str, archive_size: u64) -> Self {
+        eprint!(
+            "Extracting {resource} [{}]...",
+            indicatif::HumanBytes(archive_size)
+        );
+        io::stderr().flush().ok();
+        Self {
+            start_time: Instant::now(),
+            entries: 0,
+            bytes_since_last_update: 0,
+        }
+    }
+}
+
+impl ExtractionProgress for LightExtractionBar {
+    fn read(&mut self, bytes: usize) {
+        self.bytes_since_last_update += bytes;
+        // Update every 100 MBs of the archive.
+        if self.bytes_since_last_update > 100_000_000 {
+            eprint!(".");
+            io::stderr().flush().ok();
+            self.bytes_since_last_update = 0;
+        }
+    }
+
+    fn unpacked(&mut self) {
+        self.entries += 1;
+    }
+
+    fn finish(&mut self) {
+        let duration = Instant::now().duration_since(self.start_time);
+        eprintln!(
+            " ✓ Done! Extracted {} entries in {}",
+            self.entries,
+            indicatif::HumanDuration(duration)
+        );
+        io::stderr().flush().ok();
+    }
+
+    fn abandon(&mut self) {
+        // End the line so that whatever is printed next starts on a new one.
+        eprintln!(" ✗ Failed after extracting {} entries", self.entries);
+        io::stderr().flush().ok();
+    }
+}
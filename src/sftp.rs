@@ -0,0 +1,407 @@
+//! A blocking SFTP client built on libssh2 that fetches a single file, authenticating
+//! like OpenSSH does and checking the server's host key against `known_hosts`.
+
+use log::debug;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::convert::TryFrom;
+use std::env;
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::Error;
+
+const DEFAULT_PORT: u16 = 22;
+
+impl From<ssh2::Error> for Error {
+    fn from(err: ssh2::Error) -> Self {
+        Error::ProtocolError(format!("SSH error: {err}"))
+    }
+}
+
+/// An authenticated SFTP session.
+pub(crate) struct SftpClient {
+    // Keep the session alive as long as the SFTP channel.
+    _session: Session,
+    sftp: Sftp,
+}
+
+impl SftpClient {
+    /// Connect and authenticate to the server for the given `sftp://` URL.
+    ///
+    /// The server's host key must be present in the given `known_hosts` file, or by default
+    /// `~/.ssh/known_hosts`. If the URL contains a password, password authentication is used.
+    /// Otherwise we authenticate with the given private key, or if there isn't one, by trying
+    /// the SSH agent and then the default key files in `~/.ssh/`.
+    pub(crate) fn connect(
+        url: &reqwest::Url,
+        private_key: Option<&Path>,
+        known_hosts: Option<&Path>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let default_known_hosts = ssh_dir().map(|dir| dir.join("known_hosts"));
+        let known_hosts = known_hosts.or(default_known_hosts.as_deref());
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let user = if url.username().is_empty() {
+            env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .map_err(|_| Error::InvalidUrl(format!("missing user name in {url}")))?
+        } else {
+            decode(url.username())
+        };
+
+        debug!("Connecting to SSH server {host}:{port}");
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+            None => TcpStream::connect(addr)?,
+        };
+        let mut session = Session::new()?;
+        if let Some(timeout) = timeout {
+            session.set_timeout(u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX));
+        }
+        session.set_tcp_stream(stream);
+        session.handshake()?;
+        verify_host_key(&session, host, port, known_hosts)?;
+
+        if let Some(password) = url.password() {
+            session.userauth_password(&user, &decode(password))?;
+        } else if let Some(private_key) = private_key {
+            session.userauth_pubkey_file(&user, None, private_key, None)?;
+        } else if session.userauth_agent(&user).is_err() {
+            for key in default_private_keys() {
+                debug!("Trying SSH key {key:?}");
+                if session
+                    .userauth_pubkey_file(&user, None, &key, None)
+                    .is_ok()
+                {
+                    break;
+                }
+            }
+        }
+        if !session.authenticated() {
+            return Err(Error::ProtocolError(format!(
+                "SSH authentication failed for {user}@{host}"
+            )));
+        }
+
+        let sftp = session.sftp()?;
+        Ok(SftpClient {
+            _session: session,
+            sftp,
+        })
+    }
+
+    /// Get the size and last modification time (in seconds) of a file.
+    pub(crate) fn stat(&self, path: &Path) -> Result<(Option<u64>, Option<u64>), Error> {
+        let stat = self.sftp.stat(path)?;
+        Ok((stat.size, stat.mtime))
+    }
+
+    /// Open a file for reading.
+    pub(crate) fn open(&self, path: &Path) -> Result<impl Read, Error> {
+        Ok(self.sftp.open(path)?)
+    }
+}
+
+/// The path of the file to retrieve from an `sftp://` URL.
+pub(crate) fn url_path(url: &reqwest::Url) -> PathBuf {
+    PathBuf::from(decode(url.path()))
+}
+
+fn decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+fn ssh_dir() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(".ssh"))
+}
+
+fn default_private_keys() -> Vec<PathBuf> {
+    ssh_dir()
+        .map(|dir| {
+            ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .iter()
+                .map(|name| dir.join(name))
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn verify_host_key(
+    session: &Session,
+    host: &str,
+    port: u16,
+    known_hosts: Option<&Path>,
+) -> Result<(), Error> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| Error::ProtocolError(format!("no host key presented by {host}")))?;
+    check_host_key(session, known_hosts, host, port, key)
+}
+
+/// Check a host key against a `known_hosts` file.
+fn check_host_key(
+    session: &Session,
+    known_hosts_file: Option<&Path>,
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> Result<(), Error> {
+    let mut known_hosts = session.known_hosts()?;
+    if let Some(path) = known_hosts_file {
+        if path.is_file() {
+            known_hosts.read_file(path, KnownHostFileKind::OpenSSH)?;
+        }
+    }
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(Error::ProtocolError(format!(
+            "host key for {host} does not match the key in known_hosts"
+        ))),
+        CheckResult::NotFound => Err(Error::ProtocolError(format!(
+            "host key for {host} not found in known_hosts"
+        ))),
+        CheckResult::Failure => Err(Error::ProtocolError(format!(
+            "failed to check host key for {host}"
+        ))),
+    }
+}
+
+#[cfg(all(test, unix))]
+pub(crate) mod test_server {
+    //! Runs the system's `sshd` on a random port to test against. Tests that need it are
+    //! ignored by default, run them with `cargo test --features sftp -- --ignored sftp`.
+
+    use std::env;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tempfile::TempDir;
+
+    pub(crate) struct SshServer {
+        pub(crate) port: u16,
+        pub(crate) dir: TempDir,
+        child: Child,
+    }
+
+    impl SshServer {
+        /// Start a server that only accepts the key returned by [`SshServer::client_key()`].
+        pub(crate) fn start() -> Self {
+            let sshd = ["/usr/sbin/sshd", "/usr/local/sbin/sshd", "/usr/bin/sshd"]
+                .iter()
+                .map(Path::new)
+                .find(|path| path.is_file())
+                .expect("sshd isn't installed");
+            let dir = tempfile::tempdir().unwrap();
+            for name in ["host_key", "client_key", "other_key"] {
+                keygen(&dir.path().join(name));
+            }
+            fs::copy(
+                dir.path().join("client_key.pub"),
+                dir.path().join("authorized_keys"),
+            )
+            .unwrap();
+
+            let port = TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            let config = dir.path().join("sshd_config");
+            fs::write(
+                &config,
+                format!(
+                    "Port {port}\n\
+                     ListenAddress 127.0.0.1\n\
+                     HostKey {dir}/host_key\n\
+                     PidFile {dir}/sshd.pid\n\
+                     AuthorizedKeysFile {dir}/authorized_keys\n\
+                     StrictModes no\n\
+                     UsePAM no\n\
+                     PasswordAuthentication no\n\
+                     Subsystem sftp internal-sftp\n",
+                    dir = dir.path().display()
+                ),
+            )
+            .unwrap();
+            // `sshd` needs to be started with an absolute path.
+            let child = Command::new(sshd)
+                .arg("-D")
+                .arg("-e")
+                .arg("-f")
+                .arg(&config)
+                .stdin(Stdio::null())
+                .spawn()
+                .unwrap();
+
+            let start = Instant::now();
+            while TcpStream::connect(("127.0.0.1", port)).is_err() {
+                assert!(
+                    start.elapsed() < Duration::from_secs(10),
+                    "sshd didn't start"
+                );
+                thread::sleep(Duration::from_millis(50));
+            }
+            SshServer { port, dir, child }
+        }
+
+        /// The URL of a file on the server, logging in as the current user.
+        pub(crate) fn url(&self, path: &Path) -> String {
+            let user = env::var("USER").unwrap();
+            format!("sftp://{user}@127.0.0.1:{}{}", self.port, path.display())
+        }
+
+        pub(crate) fn client_key(&self) -> PathBuf {
+            self.dir.path().join("client_key")
+        }
+
+        /// A key the server doesn't accept.
+        pub(crate) fn other_key(&self) -> PathBuf {
+            self.dir.path().join("other_key")
+        }
+
+        /// Write a `known_hosts` file with the server's host key.
+        pub(crate) fn known_hosts(&self) -> PathBuf {
+            let key = fs::read_to_string(self.dir.path().join("host_key.pub")).unwrap();
+            let path = self.dir.path().join("known_hosts");
+            fs::write(&path, format!("[127.0.0.1]:{} {}", self.port, key)).unwrap();
+            path
+        }
+    }
+
+    impl Drop for SshServer {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    fn keygen(path: &Path) {
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use super::test_server::SshServer;
+    use super::*;
+
+    #[test]
+    fn test_url_path() {
+        let url = reqwest::Url::parse("sftp://user@host/data/my%20file.txt").unwrap();
+        assert_eq!(url_path(&url), PathBuf::from("/data/my file.txt"));
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        // Nothing should be listening on port 1.
+        let url = reqwest::Url::parse("sftp://user@127.0.0.1:1/file.txt").unwrap();
+        assert!(matches!(
+            SftpClient::connect(&url, None, None, Some(Duration::from_secs(1))),
+            Err(Error::IoError(_))
+        ));
+    }
+
+    /// An SSH public key blob of the given type.
+    fn key_blob(kind: &str, fill: u8) -> Vec<u8> {
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&(kind.len() as u32).to_be_bytes());
+        blob.extend_from_slice(kind.as_bytes());
+        blob.extend_from_slice(&32u32.to_be_bytes());
+        blob.extend_from_slice(&[fill; 32]);
+        blob
+    }
+
+    #[test]
+    fn test_check_host_key() {
+        use base64::Engine;
+
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = dir.path().join("known_hosts");
+        let key = key_blob("ssh-ed25519", 1);
+        std::fs::write(
+            &known_hosts,
+            format!(
+                "example.com ssh-ed25519 {}\n",
+                base64::engine::general_purpose::STANDARD.encode(&key)
+            ),
+        )
+        .unwrap();
+        let session = Session::new().unwrap();
+
+        check_host_key(&session, Some(&known_hosts), "example.com", 22, &key).unwrap();
+
+        let other_key = key_blob("ssh-ed25519", 2);
+        let err = check_host_key(&session, Some(&known_hosts), "example.com", 22, &other_key)
+            .unwrap_err();
+        assert!(matches!(&err, Error::ProtocolError(msg) if msg.contains("does not match")));
+
+        let err =
+            check_host_key(&session, Some(&known_hosts), "example.org", 22, &key).unwrap_err();
+        assert!(matches!(&err, Error::ProtocolError(msg) if msg.contains("not found")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs sshd"]
+    fn test_auth_failure() {
+        let server = SshServer::start();
+        let url = reqwest::Url::parse(&server.url(Path::new("/file.txt"))).unwrap();
+        let result = SftpClient::connect(
+            &url,
+            Some(&server.other_key()),
+            Some(&server.known_hosts()),
+            Some(Duration::from_secs(10)),
+        );
+        assert!(matches!(result, Err(Error::ProtocolError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[ignore = "needs sshd"]
+    fn test_download() {
+        let server = SshServer::start();
+        let path = server.dir.path().join("hello.txt");
+        std::fs::write(&path, "Hello, World!\n").unwrap();
+        let url = reqwest::Url::parse(&server.url(&path)).unwrap();
+
+        let sftp = SftpClient::connect(
+            &url,
+            Some(&server.client_key()),
+            Some(&server.known_hosts()),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap();
+        let (size, mtime) = sftp.stat(&url_path(&url)).unwrap();
+        assert_eq!(size, Some(14));
+        assert!(mtime.is_some());
+
+        let mut contents = String::new();
+        sftp.open(&url_path(&url))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Hello, World!\n");
+    }
+}
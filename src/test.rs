@@ -1,4 +1,4 @@
-use crate::{meta::Meta, Cache, Options};
+use crate::{meta::Meta, Cache, MetadataPolicy, Options};
 use httpmock::prelude::*;
 use httpmock::Method::{GET, HEAD};
 use httpmock::Mock;
@@ -235,28 +235,730 @@ fn assert_extract_archive(filename: &str) {
     assert!(sample_file_path.is_file());
 }
 
+#[test]
+fn test_decompress_gz() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let path = cache
+        .cached_path_with_options(
+            "test_fixtures/utf-8_sample/utf-8_sample.txt.gz",
+            &Options::default().decompress(),
+        )
+        .unwrap();
+    assert!(path.is_file());
+    assert!(path.to_str().unwrap().ends_with("-decompressed"));
+    assert!(path.starts_with(cache_dir.path()));
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap()
+    );
+
+    // Calling again should reuse the decompressed file.
+    let path2 = cache
+        .cached_path_with_options(
+            "test_fixtures/utf-8_sample/utf-8_sample.txt.gz",
+            &Options::default().decompress(),
+        )
+        .unwrap();
+    assert_eq!(path, path2);
+}
+
+#[test]
+fn test_decompress_remote_gz() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/data.txt.gz");
+        then.status(200)
+            .header("ETag", "fake-etag")
+            .body(fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt.gz").unwrap());
+    });
+    let mock_head = server.mock(|when, then| {
+        when.method(HEAD).path("/data.txt.gz");
+        then.status(200).header("ETag", "fake-etag");
+    });
+
+    let path = cache
+        .cached_path_with_options(
+            &server.url("/data.txt.gz"),
+            &Options::default().decompress(),
+        )
+        .unwrap();
+    mock.assert();
+    mock_head.assert();
+    assert!(path.to_str().unwrap().ends_with("-decompressed"));
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap()
+    );
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_decompress_zst() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let contents = fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap();
+    let resource = cache_dir.path().join("utf-8_sample.txt.zst");
+    fs::write(&resource, zstd::encode_all(&contents[..], 3).unwrap()).unwrap();
+
+    let path = cache
+        .cached_path_with_options(resource.to_str().unwrap(), &Options::default().decompress())
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), contents);
+}
+
+#[test]
+fn test_decompress_uncompressed_file() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let result = cache.cached_path_with_options(
+        "test_fixtures/utf-8_sample/utf-8_sample.txt",
+        &Options::default().decompress(),
+    );
+    assert!(matches!(result, Err(crate::Error::ExtractionError(_))));
+
+    let result = cache.cached_path_with_options(
+        "test_fixtures/utf-8_sample/utf-8_sample.txt.gz",
+        &Options::default().decompress().extract(),
+    );
+    assert!(matches!(result, Err(crate::Error::ConfigurationError(_))));
+}
+
+#[test]
+fn test_extract_tar() {
+    assert_extract_archive("utf-8.tar");
+}
+
+#[test]
+fn test_extract_with_limits() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .max_archive_entries(2)
+        .build()
+        .unwrap();
+
+    let result = cache.cached_path_with_options(
+        "test_fixtures/utf-8_sample/archives/utf-8.tar.gz",
+        &Options::default().extract(),
+    );
+    assert!(matches!(
+        result,
+        Err(crate::Error::ExtractionLimitExceeded(_))
+    ));
+
+    // Nothing should be left behind, so a later extraction with different limits works.
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .max_extracted_size(1 << 20)
+        .max_compression_ratio(100.0)
+        .build()
+        .unwrap();
+    let path = cache
+        .cached_path_with_options(
+            "test_fixtures/utf-8_sample/archives/utf-8.tar.gz",
+            &Options::default().extract(),
+        )
+        .unwrap();
+    assert!(path.join("dummy.txt").is_file());
+}
+
+#[test]
+fn test_archive_member() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let path = cache
+        .cached_path("test_fixtures/utf-8_sample/archives/utf-8.tar.gz!folder/utf-8_sample.txt")
+        .unwrap();
+    assert!(path.is_file());
+    assert!(path.starts_with(cache_dir.path()));
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap()
+    );
+
+    // Other files in the same archive reuse the extraction directory.
+    let dummy = cache
+        .cached_path("test_fixtures/utf-8_sample/archives/utf-8.tar.gz!dummy.txt")
+        .unwrap();
+    assert_eq!(
+        dummy.parent().unwrap(),
+        path.parent().unwrap().parent().unwrap()
+    );
+
+    let result = cache.cached_path("test_fixtures/utf-8_sample/archives/utf-8.tar.gz!missing.txt");
+    assert!(matches!(
+        result,
+        Err(crate::Error::ArchiveMemberNotFound(_))
+    ));
+
+    let result = cache.cached_path("test_fixtures/utf-8_sample/archives/utf-8.tar.gz!../x");
+    assert!(matches!(result, Err(crate::Error::InvalidUrl(_))));
+}
+
+#[test]
+fn test_remote_archive_member() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/utf-8.tar.gz");
+        then.status(200)
+            .header(ETAG_KEY, "fake-etag")
+            .body(fs::read("test_fixtures/utf-8_sample/archives/utf-8.tar.gz").unwrap());
+    });
+    let mock_head = server.mock(|when, then| {
+        when.method(HEAD).path("/utf-8.tar.gz");
+        then.status(200).header(ETAG_KEY, "fake-etag");
+    });
+
+    let path = cache
+        .cached_path(&format!("{}!dummy.txt", server.url("/utf-8.tar.gz")))
+        .unwrap();
+    mock.assert();
+    mock_head.assert();
+    assert!(path.is_file());
+    assert_eq!(path.file_name().unwrap(), "dummy.txt");
+    assert!(path
+        .parent()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .ends_with("-extracted"));
+}
+
+#[test]
+fn test_exclamation_mark_in_url() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    // A '!' in a URL that doesn't point to an archive is just part of the URL.
+    let server = MockServer::start();
+    let mock = server.mock(|when, then| {
+        when.method(GET).path("/hello!world.txt");
+        then.status(200)
+            .header(ETAG_KEY, "fake-etag")
+            .body("Hello, World!");
+    });
+    let mock_head = server.mock(|when, then| {
+        when.method(HEAD).path("/hello!world.txt");
+        then.status(200).header(ETAG_KEY, "fake-etag");
+    });
+
+    let path = cache.cached_path(&server.url("/hello!world.txt")).unwrap();
+    mock.assert();
+    mock_head.assert();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "Hello, World!");
+
+    let path = cache.cached_path("data:,a!b").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "a!b");
+}
+
+#[test]
+fn test_stream_extract() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let server = MockServer::start();
+    for fixture in ["utf-8.tar.gz", "utf-8.zip"] {
+        let path = format!("/{fixture}");
+        let mock = server.mock(|when, then| {
+            when.method(GET).path(&path);
+            then.status(200)
+                .header(ETAG_KEY, "fake-etag")
+                .body(fs::read(format!("test_fixtures/utf-8_sample/archives/{fixture}")).unwrap());
+        });
+        let mock_head = server.mock(|when, then| {
+            when.method(HEAD).path(&path);
+            then.status(200).header(ETAG_KEY, "fake-etag");
+        });
+
+        // The zip is extracted after it's downloaded instead.
+        let options = Options::default().extract().stream_extract();
+        let extracted = cache
+            .cached_path_with_options(&server.url(&path), &options)
+            .unwrap();
+        mock.assert();
+        mock_head.assert();
+        assert!(extracted.join("dummy.txt").is_file());
+        assert!(extracted.join("folder").join("utf-8_sample.txt").is_file());
+
+        // The archive itself is cached too.
+        let cached = cache.cached_path(&server.url(&path)).unwrap();
+        assert_eq!(
+            fs::read(cached).unwrap(),
+            fs::read(format!("test_fixtures/utf-8_sample/archives/{fixture}")).unwrap()
+        );
+        mock.assert();
+    }
+}
+
+#[test]
+fn test_verify_and_force_extract() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let resource = "test_fixtures/utf-8_sample/archives/utf-8.tar.gz";
+
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    let manifest = PathBuf::from(format!("{}.manifest", path.to_str().unwrap()));
+    assert!(manifest.is_file());
+
+    // Damage is only noticed when verifying.
+    fs::remove_file(path.join("dummy.txt")).unwrap();
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    assert!(!path.join("dummy.txt").exists());
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract().verify_extraction())
+        .unwrap();
+    assert!(path.join("dummy.txt").is_file());
+
+    // Now there are digests in the manifest, so a modification of the same size is caught.
+    let sample = path.join("folder").join("utf-8_sample.txt");
+    let contents = fs::read(&sample).unwrap();
+    fs::write(&sample, vec![b'x'; contents.len()]).unwrap();
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract().verify_extraction())
+        .unwrap();
+    assert_eq!(fs::read(&sample).unwrap(), contents);
+
+    // Forcing replaces the directory entirely.
+    fs::write(path.join("extra.txt"), "extra").unwrap();
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract().force_extract())
+        .unwrap();
+    assert!(!path.join("extra.txt").exists());
+    assert!(path.join("dummy.txt").is_file());
+    assert!(manifest.is_file());
+}
+
+#[test]
+fn test_extract_with_filters() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let resource = "test_fixtures/utf-8_sample/archives/utf-8.tar.gz";
+
+    let full = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    let included = cache
+        .cached_path_with_options(resource, &Options::default().extract().include("folder/**"))
+        .unwrap();
+    let excluded = cache
+        .cached_path_with_options(resource, &Options::default().extract().exclude("*.txt"))
+        .unwrap();
+
+    // Each subset gets its own directory.
+    assert_ne!(full, included);
+    assert_ne!(full, excluded);
+    assert_ne!(included, excluded);
+    assert!(included.to_str().unwrap().ends_with("-extracted"));
+
+    assert!(full.join("dummy.txt").is_file());
+    assert!(full.join("folder").join("utf-8_sample.txt").is_file());
+
+    assert!(!included.join("dummy.txt").exists());
+    assert!(included.join("folder").join("utf-8_sample.txt").is_file());
+
+    // '*' doesn't match across directories.
+    assert!(!excluded.join("dummy.txt").exists());
+    assert!(excluded.join("folder").join("utf-8_sample.txt").is_file());
+
+    // The same filters map to the same directory.
+    let included2 = cache
+        .cached_path_with_options(resource, &Options::default().extract().include("folder/**"))
+        .unwrap();
+    assert_eq!(included, included2);
+
+    let result =
+        cache.cached_path_with_options(resource, &Options::default().extract().include("[a"));
+    assert!(matches!(result, Err(crate::Error::ConfigurationError(_))));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_extract_read_only() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let resource = "test_fixtures/metadata/metadata.zip";
+
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    let default = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    assert_eq!(mode(&default.join("data.txt")), 0o640);
+
+    let read_only = cache
+        .cached_path_with_options(
+            resource,
+            &Options::default()
+                .extract()
+                .metadata(MetadataPolicy::ReadOnly),
+        )
+        .unwrap();
+    assert_ne!(default, read_only);
+    assert_eq!(mode(&read_only.join("data.txt")), 0o444);
+    assert_eq!(mode(&read_only.join("bin").join("run.sh")), 0o555);
+
+    // Read-only files don't get in the way of extracting again.
+    let again = cache
+        .cached_path_with_options(
+            resource,
+            &Options::default()
+                .extract()
+                .metadata(MetadataPolicy::ReadOnly)
+                .force_extract(),
+        )
+        .unwrap();
+    assert_eq!(read_only, again);
+    assert_eq!(mode(&again.join("data.txt")), 0o444);
+}
+
+#[test]
+fn test_extract_local_reuses_dir() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let fixtures = Path::new("test_fixtures/utf-8_sample/archives");
+    let archive_dir = tempdir().unwrap();
+    let archive = archive_dir.path().join("archive.tar.gz");
+    fs::copy(fixtures.join("utf-8.tar.gz"), &archive).unwrap();
+    let resource = archive.to_str().unwrap();
+    let set_mtime = |secs| {
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        fs::File::options()
+            .write(true)
+            .open(&archive)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    };
+
+    // The same version of the archive is extracted once, no matter how much time passes.
+    let first = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    fs::write(first.join("marker"), "").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    let second = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    assert_eq!(first, second);
+    assert!(second.join("marker").is_file());
+
+    // Touching the archive makes it a new version.
+    set_mtime(1_000_000_000);
+    let touched = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    assert_ne!(first, touched);
+    assert!(touched.join("dummy.txt").is_file());
+
+    // Unless versions are told apart by their contents.
+    let options = Options::default().extract().local_content_hash();
+    let hashed = cache.cached_path_with_options(resource, &options).unwrap();
+    set_mtime(2_000_000_000);
+    assert_eq!(
+        hashed,
+        cache.cached_path_with_options(resource, &options).unwrap()
+    );
+    fs::copy(fixtures.join("utf-8.tar"), &archive).unwrap();
+    let replaced = cache.cached_path_with_options(resource, &options).unwrap();
+    assert_ne!(hashed, replaced);
+    assert!(replaced.join("dummy.txt").is_file());
+}
+
+#[test]
+fn test_extract_with_strip_components() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let resource = "test_fixtures/utf-8_sample/archives/utf-8.zip";
+
+    let full = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    let stripped = cache
+        .cached_path_with_options(resource, &Options::default().extract().strip_components(1))
+        .unwrap();
+    assert_ne!(full, stripped);
+    assert!(!stripped.join("dummy.txt").exists());
+    assert!(stripped.join("utf-8_sample.txt").is_file());
+
+    // There are two top-level entries, so there's nothing to unwrap.
+    let unwrapped = cache
+        .cached_path_with_options(resource, &Options::default().extract().unwrap_single_dir())
+        .unwrap();
+    assert_ne!(full, unwrapped);
+    assert!(unwrapped.join("dummy.txt").is_file());
+    assert!(unwrapped.join("folder").join("utf-8_sample.txt").is_file());
+
+    // Unless some are filtered out.
+    let unwrapped = cache
+        .cached_path_with_options(
+            resource,
+            &Options::default()
+                .extract()
+                .include("folder/**")
+                .unwrap_single_dir(),
+        )
+        .unwrap();
+    assert!(unwrapped.join("utf-8_sample.txt").is_file());
+    assert_eq!(
+        fs::read(unwrapped.join("utf-8_sample.txt")).unwrap(),
+        fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap()
+    );
+}
+
+#[test]
+fn test_extract_recursive() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    // A zip of tar.gz shards.
+    let resource = cache_dir.path().join("shards.zip");
+    let mut writer = zip::ZipWriter::new(fs::File::create(&resource).unwrap());
+    for shard in ["shards/00.tar.gz", "shards/01.tar.gz"] {
+        writer
+            .start_file(shard, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        let data = fs::read("test_fixtures/utf-8_sample/archives/utf-8.tar.gz").unwrap();
+        std::io::Write::write_all(&mut writer, &data).unwrap();
+    }
+    writer.finish().unwrap();
+    let resource = resource.to_str().unwrap();
+
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract())
+        .unwrap();
+    assert!(path.join("shards").join("00.tar.gz").is_file());
+
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().extract().recursive(1))
+        .unwrap();
+    for shard in ["00.tar.gz", "01.tar.gz"] {
+        let shard = path.join("shards").join(shard);
+        assert!(shard.join("dummy.txt").is_file());
+        assert!(shard.join("folder").join("utf-8_sample.txt").is_file());
+    }
+}
+
+#[test]
+fn test_extract_with_archive_format() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    // A zip that starts like an EPUB, so it isn't detected as a plain zip.
+    let resource = cache_dir.path().join("book.epub");
+    let mut writer = zip::ZipWriter::new(fs::File::create(&resource).unwrap());
+    let stored =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    writer.start_file("mimetype", stored).unwrap();
+    std::io::Write::write_all(&mut writer, b"application/epub+zip").unwrap();
+    writer.start_file("chapter.txt", stored).unwrap();
+    std::io::Write::write_all(&mut writer, b"Once upon a time").unwrap();
+    writer.finish().unwrap();
+    let resource = resource.to_str().unwrap();
+
+    let result = cache.cached_path_with_options(resource, &Options::default().extract());
+    assert!(matches!(result, Err(crate::Error::ExtractionError(_))));
+
+    let path = cache
+        .cached_path_with_options(
+            resource,
+            &Options::default().extract().archive_format("zip"),
+        )
+        .unwrap();
+    assert!(path.join("chapter.txt").is_file());
+
+    // The data has to match the format.
+    let result = cache.cached_path_with_options(
+        "test_fixtures/utf-8_sample/archives/utf-8.zip",
+        &Options::default().extract().archive_format("tar.gz"),
+    );
+    assert!(matches!(result, Err(crate::Error::ExtractionError(_))));
+
+    let result = cache.cached_path_with_options(
+        resource,
+        &Options::default().extract().archive_format("rar"),
+    );
+    assert!(matches!(result, Err(crate::Error::ConfigurationError(_))));
+}
+
 #[test]
 fn test_extract_tar_gz() {
     assert_extract_archive("utf-8.tar.gz");
 }
 
-#[cfg(feature = "lzma")]
+#[cfg(any(feature = "lzma", feature = "xz-native"))]
 #[test]
 fn test_extract_tar_xz() {
     assert_extract_archive("utf-8.tar.xz");
 }
 
-#[cfg(feature = "lzma")]
+#[cfg(any(feature = "lzma", feature = "xz-native"))]
 #[test]
 fn test_extract_tar_lzma() {
     assert_extract_archive("utf-8.tar.lzma");
 }
 
+#[cfg(feature = "bzip2")]
+#[test]
+fn test_extract_tar_bz2() {
+    assert_extract_archive("utf-8.tar.bz2");
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_extract_tar_zst() {
+    assert_extract_archive("utf-8.tar.zst");
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_extract_tar_zst_multi_frame_long_window() {
+    use flate2::read::GzDecoder;
+    use std::io::{Read, Write};
+
+    let mut tar = vec![];
+    GzDecoder::new(fs::File::open("test_fixtures/utf-8_sample/archives/utf-8.tar.gz").unwrap())
+        .read_to_end(&mut tar)
+        .unwrap();
+
+    // Compress the tarball as two separate frames with a window larger than the default
+    // maximum the decoder accepts.
+    let archive_dir = tempdir().unwrap();
+    let archive_path = archive_dir.path().join("utf-8.tar.zst");
+    let mut archive = fs::File::create(&archive_path).unwrap();
+    let (first, second) = tar.split_at(tar.len() / 2);
+    for chunk in [first, second] {
+        let mut encoder = zstd::Encoder::new(&mut archive, 3).unwrap();
+        encoder.long_distance_matching(true).unwrap();
+        encoder.window_log(28).unwrap();
+        encoder.write_all(chunk).unwrap();
+        encoder.finish().unwrap();
+    }
+    drop(archive);
+
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let path = cache
+        .cached_path_with_options(
+            archive_path.to_str().unwrap(),
+            &Options::default().extract(),
+        )
+        .unwrap();
+    assert!(path.join("dummy.txt").is_file());
+    assert!(path.join("folder").join("utf-8_sample.txt").is_file());
+}
+
 #[test]
 fn test_extract_zip() {
     assert_extract_archive("utf-8.zip");
 }
 
+#[cfg(feature = "sevenz")]
+#[test]
+fn test_extract_7z() {
+    assert_extract_archive("utf-8.7z");
+
+    // The fixture is a solid LZMA2 archive, so make sure files after the first one
+    // in the solid block come out intact as well.
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+    let path = cache
+        .cached_path_with_options(
+            "test_fixtures/utf-8_sample/archives/utf-8.7z",
+            &Options::default().extract(),
+        )
+        .unwrap();
+    assert_eq!(
+        fs::read(path.join("folder").join("utf-8_sample.txt")).unwrap(),
+        fs::read("test_fixtures/utf-8_sample/utf-8_sample.txt").unwrap()
+    );
+}
+
 #[test]
 fn test_extract_in_subdir() {
     let cache_dir = tempdir().unwrap();
@@ -292,3 +994,409 @@ fn test_extract_in_subdir() {
     let sample_file_path = path.join("dummy.txt");
     assert!(sample_file_path.is_file());
 }
+
+#[test]
+fn test_get_cached_path_local_file_url() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let local_path = fs::canonicalize("README.md").unwrap();
+    let url = reqwest::Url::from_file_path(&local_path).unwrap();
+    let path = cache.cached_path(url.as_str()).unwrap();
+    assert_eq!(path, local_path);
+}
+
+#[test]
+fn test_extract_local_file_with_env_var() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    std::env::set_var("CACHED_PATH_TEST_FIXTURES", "test_fixtures");
+    let resource = "$CACHED_PATH_TEST_FIXTURES/utf-8_sample/archives/utf-8.tar.gz";
+
+    // Without expansion the variable is taken literally.
+    assert!(cache.cached_path(resource).is_err());
+
+    let path = cache
+        .cached_path_with_options(resource, &Options::default().expand_vars().extract())
+        .unwrap();
+    assert!(path.is_dir());
+    assert!(path.join("dummy.txt").is_file());
+}
+
+#[cfg(feature = "ftp")]
+#[test]
+fn test_cached_path_ftp_file() {
+    use crate::ftp::test_server::FtpServer;
+
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let server = FtpServer::start("/hello.txt", b"Hello, World!\n".to_vec(), "20200101000000");
+    let resource = format!("ftp://127.0.0.1:{}/hello.txt", server.port);
+
+    let path = cache.cached_path(&resource).unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    assert_eq!(&contents, "Hello, World!\n");
+    let meta = Meta::from_cache(&path).unwrap();
+    assert_eq!(meta.etag.as_deref(), Some("14-20200101000000"));
+
+    // The size and modification time haven't changed, so we should get the same path back.
+    let same_path = cache.cached_path(&resource).unwrap();
+    assert_eq!(same_path, path);
+
+    // But a new modification time means a new version.
+    let server = FtpServer::start("/hello.txt", b"Hello, World!\n".to_vec(), "20210101000000");
+    let resource = format!("ftp://127.0.0.1:{}/hello.txt", server.port);
+    let new_path = cache.cached_path(&resource).unwrap();
+    assert_ne!(new_path, path);
+}
+
+#[cfg(all(feature = "sftp", unix))]
+#[test]
+#[ignore = "needs sshd"]
+fn test_cached_path_sftp_file() {
+    use crate::sftp::test_server::SshServer;
+    use std::time::{Duration, SystemTime};
+
+    let server = SshServer::start();
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .sftp_private_key(server.client_key())
+        .sftp_known_hosts(server.known_hosts())
+        .build()
+        .unwrap();
+
+    let file = server.dir.path().join("hello.txt");
+    fs::write(&file, "Hello, World!\n").unwrap();
+    let set_mtime = |secs| {
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    };
+    set_mtime(1_577_836_800);
+    let resource = server.url(&file);
+
+    let path = cache.cached_path(&resource).unwrap();
+    let contents = fs::read_to_string(&path).unwrap();
+    assert_eq!(&contents, "Hello, World!\n");
+    let meta = Meta::from_cache(&path).unwrap();
+    assert_eq!(meta.etag.as_deref(), Some("14-1577836800"));
+
+    // The size and modification time haven't changed, so we should get the same path back.
+    let same_path = cache.cached_path(&resource).unwrap();
+    assert_eq!(same_path, path);
+
+    // But a new modification time means a new version.
+    set_mtime(1_609_459_200);
+    let new_path = cache.cached_path(&resource).unwrap();
+    assert_ne!(new_path, path);
+}
+
+#[cfg(not(feature = "ftp"))]
+#[test]
+fn test_cached_path_ftp_requires_feature() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let result = cache.cached_path("ftp://127.0.0.1/hello.txt");
+    assert!(matches!(result, Err(crate::Error::ConfigurationError(_))));
+}
+
+#[cfg(feature = "git")]
+fn git_commit(repo: &git2::Repository, files: &[(&str, &str)]) -> git2::Oid {
+    let workdir = repo.workdir().unwrap();
+    let mut index = repo.index().unwrap();
+    for (name, contents) in files {
+        let path = workdir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        index.add_path(Path::new(name)).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = git2::Signature::now("test", "test@example.com").unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        "commit",
+        &tree,
+        &parents,
+    )
+    .unwrap()
+}
+
+#[cfg(feature = "git")]
+#[test]
+fn test_cached_path_git_repo() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let repo_dir = tempdir().unwrap();
+    let repo = git2::Repository::init(repo_dir.path()).unwrap();
+    let first_commit = git_commit(&repo, &[("configs/model.json", "{\"version\": 1}")]);
+    let branch = repo.head().unwrap().shorthand().unwrap().to_string();
+    let repo_url = reqwest::Url::from_directory_path(repo_dir.path()).unwrap();
+
+    // The whole repository at the default branch.
+    let path = cache.cached_path(&format!("git+{repo_url}")).unwrap();
+    assert!(path.is_dir());
+    assert!(path.join("configs").join("model.json").is_file());
+    assert!(!path.join(".git").exists());
+
+    // A path within the repository at a branch.
+    let resource = format!("git+{repo_url}@{branch}#configs/model.json");
+    let path = cache.cached_path(&resource).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{\"version\": 1}");
+
+    // The branch has to be revalidated, but it hasn't changed.
+    let same_path = cache.cached_path(&resource).unwrap();
+    assert_eq!(same_path, path);
+
+    // Now update the branch.
+    let second_commit = git_commit(&repo, &[("configs/model.json", "{\"version\": 2}")]);
+    let new_path = cache.cached_path(&resource).unwrap();
+    assert_ne!(new_path, path);
+    assert_eq!(fs::read_to_string(&new_path).unwrap(), "{\"version\": 2}");
+    let meta = Meta::from_cache(new_path.parent().unwrap().parent().unwrap()).unwrap();
+    assert_eq!(meta.etag, Some(second_commit.to_string()));
+
+    // A path at a pinned commit.
+    let path = cache
+        .cached_path(&format!("git+{repo_url}@{first_commit}#configs/model.json"))
+        .unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "{\"version\": 1}");
+
+    // A path that doesn't exist.
+    let result = cache.cached_path(&format!("git+{repo_url}@{branch}#missing.json"));
+    assert!(matches!(result, Err(crate::Error::ResourceNotFound(_))));
+}
+
+#[cfg(feature = "git")]
+#[test]
+fn test_cached_path_git_repo_fresh() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .freshness_lifetime(300)
+        .build()
+        .unwrap();
+
+    let repo_dir = tempdir().unwrap();
+    let repo = git2::Repository::init(repo_dir.path()).unwrap();
+    git_commit(&repo, &[("hello.txt", "Hello, World!")]);
+    let repo_url = reqwest::Url::from_directory_path(repo_dir.path()).unwrap();
+    let resource = format!("git+{repo_url}#hello.txt");
+
+    let path = cache.cached_path(&resource).unwrap();
+
+    // Within the freshness lifetime we don't see the new commit.
+    git_commit(&repo, &[("hello.txt", "Hello, again!")]);
+    let same_path = cache.cached_path(&resource).unwrap();
+    assert_eq!(same_path, path);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "Hello, World!");
+}
+
+/// Serve an OCI artifact from a mock registry that requires token authentication.
+fn mock_oci_registry<'a>(
+    server: &'a MockServer,
+    tag: &str,
+    layers: &[(&str, Vec<u8>)],
+) -> Vec<Mock<'a>> {
+    use sha2::{Digest, Sha256};
+
+    let mut mocks = vec![];
+    let mut descriptors = vec![];
+    for (title, contents) in layers {
+        let digest = format!("sha256:{:x}", Sha256::digest(contents));
+        descriptors.push(serde_json::json!({
+            "mediaType": "application/octet-stream",
+            "digest": digest,
+            "size": contents.len(),
+            "annotations": {"org.opencontainers.image.title": title},
+        }));
+        mocks.push(server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/v2/org/model/blobs/{digest}"))
+                .header("Authorization", "Bearer secret-token");
+            then.status(200).body(contents);
+        }));
+    }
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+            "size": 2,
+        },
+        "layers": descriptors,
+    })
+    .to_string();
+    mocks.push(server.mock(|when, then| {
+        when.method(GET)
+            .path(format!("/v2/org/model/manifests/{tag}"))
+            .header("Authorization", "Bearer secret-token");
+        then.status(200)
+            .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+            .body(&manifest);
+    }));
+    mocks.push(server.mock(|when, then| {
+        when.method(GET)
+            .path_includes("/v2/")
+            .header_missing("Authorization");
+        then.status(401).header(
+            "WWW-Authenticate",
+            format!(
+                "Bearer realm=\"{}\",service=\"test\",scope=\"repository:org/model:pull\"",
+                server.url("/token")
+            ),
+        );
+    }));
+    mocks.push(server.mock(|when, then| {
+        when.method(GET)
+            .path("/token")
+            .query_param("service", "test")
+            .query_param("scope", "repository:org/model:pull");
+        then.status(200).body("{\"token\": \"secret-token\"}");
+    }));
+    mocks
+}
+
+#[test]
+fn test_cached_path_oci_artifact() {
+    let server = MockServer::start();
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let archive = fs::read("test_fixtures/utf-8_sample/archives/utf-8.tar.gz").unwrap();
+    let mocks = mock_oci_registry(
+        &server,
+        "v1",
+        &[("config.json", b"{}".to_vec()), ("data.tar.gz", archive)],
+    );
+    let resource = format!("oci://127.0.0.1:{}/org/model:v1", server.port());
+
+    let path = cache.cached_path(&resource).unwrap();
+    assert!(path.is_dir());
+    assert_eq!(fs::read_to_string(path.join("config.json")).unwrap(), "{}");
+    assert!(path.join("data.tar.gz").is_file());
+    let blobs: Vec<_> = fs::read_dir(cache_dir.path().join("blobs").join("sha256"))
+        .unwrap()
+        .collect();
+    // Two layers and the manifest.
+    assert_eq!(blobs.len(), 3);
+
+    // Blobs are only downloaded once.
+    let path = cache
+        .cached_path_with_options(&resource, &Options::default().extract())
+        .unwrap();
+    assert!(path.is_dir());
+    assert!(path.join("config.json").is_file());
+    assert!(path.join("dummy.txt").is_file());
+    assert_eq!(mocks[0].calls(), 1);
+    assert_eq!(mocks[1].calls(), 1);
+}
+
+#[test]
+fn test_cached_path_oci_artifact_bad_digest() {
+    let server = MockServer::start();
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let mocks = mock_oci_registry(&server, "v1", &[("hello.txt", b"Hello, World!".to_vec())]);
+    // Serve the wrong contents for the blob.
+    let mut blob = mocks.into_iter().next().unwrap();
+    blob.delete();
+    let _bad_blob = server.mock(|when, then| {
+        when.method(GET)
+            .path_includes("/blobs/")
+            .header_exists("Authorization");
+        then.status(200).body("Goodbye, World!");
+    });
+
+    let resource = format!("oci://127.0.0.1:{}/org/model:v1", server.port());
+    let result = cache.cached_path(&resource);
+    assert!(matches!(result, Err(crate::Error::ProtocolError(_))));
+}
+
+#[test]
+fn test_cached_path_data_uri() {
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let path = cache.cached_path("data:,Hello%2C%20World!").unwrap();
+    assert!(path.starts_with(cache_dir.path()));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "Hello, World!");
+
+    // The same content always maps to the same file.
+    let same_path = cache
+        .cached_path("data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==")
+        .unwrap();
+    assert_eq!(same_path, path);
+}
+
+#[test]
+fn test_extract_data_uri() {
+    use base64::prelude::*;
+
+    let cache_dir = tempdir().unwrap();
+    let cache = Cache::builder()
+        .dir(cache_dir.path().to_owned())
+        .disable_progress_bar()
+        .build()
+        .unwrap();
+
+    let archive = fs::read("test_fixtures/utf-8_sample/archives/utf-8.tar.gz").unwrap();
+    let resource = format!(
+        "data:application/gzip;base64,{}",
+        BASE64_STANDARD.encode(archive)
+    );
+    let path = cache
+        .cached_path_with_options(&resource, &Options::default().extract())
+        .unwrap();
+    assert!(path.is_dir());
+    assert!(path.to_str().unwrap().ends_with("-extracted"));
+    assert!(path.join("dummy.txt").is_file());
+}
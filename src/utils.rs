@@ -1,6 +1,10 @@
+use base64::prelude::*;
 use sha2::{Digest, Sha256};
+use std::path::PathBuf;
 use std::time::SystemTime;
 
+use crate::Error;
+
 pub(crate) fn hash_str(s: &str) -> String {
     format!("{:x}", Sha256::digest(s.as_bytes()))
 }
@@ -12,3 +16,112 @@ pub(crate) fn now() -> f64 {
         .unwrap()
         .as_secs_f64()
 }
+
+/// Resolve a local resource to a path, normalizing `file://` URLs and optionally
+/// expanding `~` and environment variables.
+pub(crate) fn local_path(resource: &str, expand_vars: bool) -> Result<PathBuf, Error> {
+    let resource = if expand_vars {
+        shellexpand::full(resource)
+            .map_err(|e| Error::ResourceNotFound(format!("{resource} ({e})")))?
+    } else {
+        resource.into()
+    };
+
+    if resource.starts_with("file://") {
+        // `Url::to_file_path()` takes care of percent-decoding and platform-specific
+        // path conventions.
+        reqwest::Url::parse(&resource)
+            .ok()
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| Error::InvalidUrl(resource.to_string()))
+    } else {
+        Ok(PathBuf::from(resource.as_ref()))
+    }
+}
+
+/// Decode the data of a `data:[<mediatype>][;base64],<data>` URI
+/// (see [RFC 2397](https://www.rfc-editor.org/rfc/rfc2397)).
+pub(crate) fn decode_data_uri(resource: &str) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::InvalidUrl(format!("invalid data URI {}...", truncate(resource, 64)));
+    let (header, data) = resource
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(','))
+        .ok_or_else(invalid)?;
+    let data: Vec<u8> = percent_encoding::percent_decode_str(data).collect();
+    if header.ends_with(";base64") {
+        let data: Vec<u8> = data
+            .into_iter()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        BASE64_STANDARD.decode(data).map_err(|_| invalid())
+    } else {
+        Ok(data)
+    }
+}
+
+fn truncate(s: &str, max_chars: usize) -> &str {
+    match s.char_indices().nth(max_chars) {
+        Some((i, _)) => &s[..i],
+        None => s,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_path_plain() {
+        assert_eq!(
+            local_path("README.md", false).unwrap(),
+            PathBuf::from("README.md")
+        );
+        // Without expansion, `~` and variables are taken literally.
+        assert_eq!(
+            local_path("~/$FOO/x", false).unwrap(),
+            PathBuf::from("~/$FOO/x")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_local_path_file_url() {
+        assert_eq!(
+            local_path("file:///data/x.tar.gz", false).unwrap(),
+            PathBuf::from("/data/x.tar.gz")
+        );
+        assert_eq!(
+            local_path("file://localhost/data/my%20file.txt", false).unwrap(),
+            PathBuf::from("/data/my file.txt")
+        );
+        assert!(local_path("file://otherhost/data/x", false).is_err());
+    }
+
+    #[test]
+    fn test_decode_data_uri() {
+        assert_eq!(
+            decode_data_uri("data:,Hello%2C%20World!").unwrap(),
+            b"Hello, World!"
+        );
+        assert_eq!(
+            decode_data_uri("data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==").unwrap(),
+            b"Hello, World!"
+        );
+        assert!(decode_data_uri("data:;base64,!!!").is_err());
+        assert!(decode_data_uri("data:text/plain").is_err());
+    }
+
+    #[test]
+    fn test_local_path_expand_vars() {
+        std::env::set_var("CACHED_PATH_TEST_DATA_ROOT", "/data");
+        assert_eq!(
+            local_path("$CACHED_PATH_TEST_DATA_ROOT/x", true).unwrap(),
+            PathBuf::from("/data/x")
+        );
+        assert_eq!(
+            local_path("${CACHED_PATH_TEST_DATA_ROOT}/x", true).unwrap(),
+            PathBuf::from("/data/x")
+        );
+        assert!(local_path("$CACHED_PATH_TEST_UNDEFINED_VAR/x", true).is_err());
+    }
+}